@@ -0,0 +1,343 @@
+use crate::route::*;
+use crate::vehicles::*;
+use macroquad::rand::gen_range;
+use serde::Deserialize;
+use std::fs;
+
+// Cars per minute from every approach when no demand is given
+pub const DEFAULT_RATE: f32 = 20.;
+
+// How the gaps between two arrivals from the same approach are drawn
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Headway {
+    // Evenly spaced arrivals
+    Fixed,
+    // Exponential gaps, i.e. a Poisson arrival process
+    #[default]
+    Poisson,
+}
+
+// Traffic demand, loaded from a demand file such as
+// assets/demand/morning-peak.toml
+#[derive(Debug, Clone, PartialEq, Default, Deserialize)]
+pub struct Demand {
+    #[serde(default)]
+    pub headway: Headway,
+    // Shortest gap in simulated seconds between two arrivals from the
+    // same approach, the rest of the gap is drawn as `headway` says
+    #[serde(default)]
+    pub min_headway: f32,
+    #[serde(default, rename = "approach")]
+    pub approaches: Vec<ApproachDemand>,
+    // Factor the rates are multiplied with over time, interpolated between
+    // the points and held before the first and after the last
+    #[serde(default)]
+    pub profile: Vec<ProfilePoint>,
+    // Share of each vehicle class among the arrivals, and among the
+    // vehicles spawned by hand or by a scenario without a class
+    #[serde(default)]
+    pub classes: ClassMix,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+pub struct ApproachDemand {
+    pub approach: Approach,
+    // Cars per simulated minute
+    pub rate: f32,
+    #[serde(default)]
+    pub turns: TurnWeights,
+}
+
+// Relative share of the cars taking each turn
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+pub struct TurnWeights {
+    pub left: f32,
+    pub straight: f32,
+    pub right: f32,
+}
+
+impl Default for TurnWeights {
+    fn default() -> Self {
+        TurnWeights {
+            left: 1.,
+            straight: 1.,
+            right: 1.,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+pub struct ProfilePoint {
+    pub time: f32,
+    pub factor: f32,
+}
+
+impl Demand {
+    pub fn load(path: &str) -> Result<Self, String> {
+        let contents =
+            fs::read_to_string(path).map_err(|err| format!("cannot read {}: {}", path, err))?;
+        Demand::parse(&contents).map_err(|err| format!("invalid demand {}: {}", path, err))
+    }
+
+    pub fn parse(contents: &str) -> Result<Self, String> {
+        let mut demand: Demand = toml::from_str(contents).map_err(|err| err.to_string())?;
+        if !demand.min_headway.is_finite() || demand.min_headway < 0. {
+            return Err(String::from(
+                "min_headway must be a finite number of at least 0",
+            ));
+        }
+        for approach in &demand.approaches {
+            let letter = approach.approach.letter();
+            if demand
+                .approaches
+                .iter()
+                .filter(|other| other.approach == approach.approach)
+                .count()
+                > 1
+            {
+                return Err(format!("approach {} is defined more than once", letter));
+            }
+            if !approach.rate.is_finite() || approach.rate < 0. {
+                return Err(format!(
+                    "approach {} has a rate that is not a finite number of at least 0",
+                    letter
+                ));
+            }
+            let turns = approach.turns;
+            if turns.left < 0. || turns.straight < 0. || turns.right < 0. {
+                return Err(format!("approach {} has a negative turn weight", letter));
+            }
+        }
+        if VehicleClass::ALL
+            .into_iter()
+            .any(|class| demand.classes.weight(class) < 0.)
+        {
+            return Err(String::from("class weights must not be negative"));
+        }
+        if demand
+            .profile
+            .iter()
+            .any(|point| !point.time.is_finite() || !point.factor.is_finite() || point.factor < 0.)
+        {
+            return Err(String::from(
+                "profile times and factors must be finite and factors not negative",
+            ));
+        }
+        demand
+            .profile
+            .sort_by(|point, other| point.time.total_cmp(&other.time));
+        Ok(demand)
+    }
+
+    // The same rate and equal turn shares on every approach
+    pub fn uniform(rate: f32) -> Self {
+        Demand {
+            approaches: Approach::ALL
+                .into_iter()
+                .map(|approach| ApproachDemand {
+                    approach,
+                    rate,
+                    turns: TurnWeights::default(),
+                })
+                .collect(),
+            ..Default::default()
+        }
+    }
+
+    // Multiplier of the rates at simulated time `now`
+    pub fn factor_at(&self, now: f32) -> f32 {
+        let (Some(first), Some(last)) = (self.profile.first(), self.profile.last()) else {
+            return 1.;
+        };
+        if now <= first.time {
+            return first.factor;
+        }
+        if now >= last.time {
+            return last.factor;
+        }
+        let after = self.profile.partition_point(|point| point.time <= now);
+        let (from, to) = (self.profile[after - 1], self.profile[after]);
+        from.factor + (to.factor - from.factor) * (now - from.time) / (to.time - from.time)
+    }
+}
+
+impl TurnWeights {
+    // A turn drawn in proportion to the weights, an equal share if they
+    // are all zero
+    pub fn pick(&self) -> Turn {
+        let total = self.left + self.straight + self.right;
+        if total <= 0. {
+            return Turn::ALL[gen_range(0, 3)];
+        }
+        let draw = gen_range(0., total);
+        if draw < self.left {
+            Turn::Left
+        } else if draw < self.left + self.straight {
+            Turn::Straight
+        } else {
+            Turn::Right
+        }
+    }
+}
+
+// Draws the arrivals of a Demand as the simulation goes. The gap to the
+// next arrival uses the rate at the time of the current one, which is
+// close enough as long as the profile changes slowly compared to the gaps.
+pub struct ArrivalGenerator {
+    demand: Demand,
+    // Simulated time of the next arrival per entry of demand.approaches,
+    // None while there is none drawn
+    next_arrivals: Vec<Option<f32>>,
+}
+
+impl ArrivalGenerator {
+    pub fn new(demand: Demand) -> Self {
+        ArrivalGenerator {
+            next_arrivals: vec![None; demand.approaches.len()],
+            demand,
+        }
+    }
+
+    // A class drawn from the class mix
+    pub fn class(&self) -> VehicleClass {
+        self.demand.classes.pick()
+    }
+
+    // A route from `approach` with a turn drawn from its turn weights
+    pub fn route_from(&self, approach: Approach) -> Route {
+        let turns = self
+            .demand
+            .approaches
+            .iter()
+            .find(|demand| demand.approach == approach)
+            .map(|demand| demand.turns)
+            .unwrap_or_default();
+        Route::new(approach, turns.pick())
+    }
+
+    // Routes of the cars arriving up to `now`
+    pub fn arrivals(&mut self, now: f32) -> Vec<Route> {
+        let mut routes = Vec::new();
+        for (index, approach) in self.demand.approaches.iter().enumerate() {
+            loop {
+                let time = match self.next_arrivals[index] {
+                    Some(time) => time,
+                    None => {
+                        let rate = approach.rate * self.demand.factor_at(now);
+                        if rate <= 0. {
+                            break;
+                        }
+                        now + gap(&self.demand, rate)
+                    }
+                };
+                self.next_arrivals[index] = Some(time);
+                if time > now {
+                    break;
+                }
+                routes.push(Route::new(approach.approach, approach.turns.pick()));
+                // Without demand the next arrival is drawn once there is
+                // some again
+                let rate = approach.rate * self.demand.factor_at(time);
+                self.next_arrivals[index] = (rate > 0.).then(|| time + gap(&self.demand, rate));
+            }
+        }
+        routes
+    }
+}
+
+// Shortest gap ever drawn, so that the time of the next arrival always
+// moves on however high the rate gets
+const MIN_GAP: f32 = 1e-3;
+
+// Simulated seconds to the next arrival at `rate` cars per minute
+fn gap(demand: &Demand, rate: f32) -> f32 {
+    let mean = 60. / rate;
+    let min_headway = demand.min_headway.min(mean);
+    let gap = match demand.headway {
+        Headway::Fixed => mean,
+        Headway::Poisson => min_headway - (mean - min_headway) * (1. - gen_range(0., 1.0_f32)).ln(),
+    };
+    // Written this way round to also catch a NaN gap
+    if gap >= MIN_GAP {
+        gap
+    } else {
+        MIN_GAP
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::simulation::TIME_STEP;
+
+    // The profile is sorted by time, and the factor is held outside of it
+    // and interpolated within
+    #[test]
+    fn demand_profile_is_sorted_and_interpolated() {
+        let demand = Demand::parse(
+            r#"
+            [[approach]]
+            approach = "L"
+            rate = 10.0
+
+            [[profile]]
+            time = 100.0
+            factor = 2.0
+
+            [[profile]]
+            time = 0.0
+            factor = 1.0
+            "#,
+        )
+        .unwrap();
+        assert_eq!(demand.headway, Headway::Poisson);
+        assert_eq!(demand.approaches[0].turns, TurnWeights::default());
+        assert_eq!(demand.factor_at(-5.), 1.);
+        assert_eq!(demand.factor_at(25.), 1.25);
+        assert_eq!(demand.factor_at(500.), 2.);
+    }
+
+    // Demands that would give no sensible arrivals are refused
+    #[test]
+    fn invalid_demands_are_refused() {
+        let approach = |fields: &str| format!("[[approach]]\napproach = \"L\"\n{}", fields);
+        for contents in [
+            String::from("min_headway = -1.0"),
+            approach("rate = -1.0"),
+            approach("rate = inf"),
+            approach("rate = nan"),
+            format!("{}\n{}", approach("rate = 1.0"), approach("rate = 2.0")),
+            approach("rate = 1.0\nturns = { left = -1.0, straight = 1.0, right = 1.0 }"),
+            String::from("[classes]\nbus = -1.0"),
+            String::from("[[profile]]\ntime = 0.0\nfactor = -1.0"),
+        ] {
+            assert!(Demand::parse(&contents).is_err(), "{}", contents);
+        }
+    }
+
+    // Poisson arrivals come at the rate asked for on average, and never
+    // closer together than the shortest headway
+    #[test]
+    fn poisson_arrivals_keep_rate_and_min_headway() {
+        let mut demand = Demand::uniform(0.);
+        demand.approaches.truncate(1);
+        demand.approaches[0].rate = 60.;
+        demand.min_headway = 0.5;
+        let mut generator = ArrivalGenerator::new(demand);
+
+        let mut times = Vec::new();
+        for step in 0..60 * 600 {
+            let now = step as f32 * TIME_STEP;
+            for _ in generator.arrivals(now) {
+                times.push(now);
+            }
+        }
+        // 600 expected, with a standard deviation of about 25
+        assert!((500..=700).contains(&times.len()), "{}", times.len());
+        let gaps: Vec<f32> = times.windows(2).map(|pair| pair[1] - pair[0]).collect();
+        assert!(gaps.iter().all(|&gap| gap >= 0.5 - TIME_STEP));
+        // Unlike evenly spaced arrivals some gaps are much longer than the mean
+        assert!(gaps.iter().any(|&gap| gap > 2.));
+    }
+}
@@ -1,596 +1,488 @@
+use crate::geometry::*;
+use crate::layout::*;
+use crate::path::*;
+use crate::route::*;
+use crate::simulation::TIME_STEP;
+use crate::spatial::*;
 use crate::stats::*;
-use macroquad::{prelude::*, rand::gen_range};
-use std::time::Instant;
-use uuid::Uuid;
+use crate::vehicles::*;
+use macroquad::prelude::*;
+use std::collections::HashMap;
+use std::f32::consts::PI;
+use std::sync::Arc;
 
 pub const CAR_SIZE: Vec2 = vec2(43., 33.);
-pub const RADAR_SIZE: Vec2 = vec2(43., 33.);
+// Cruising speed range in pixels per simulated second, MAX_SPEED is the
+// top speed of the fastest class
+pub const MIN_SPEED: f32 = 48.;
+pub const MAX_SPEED: f32 = 120.;
+// Car following of the car class, in pixels and simulated seconds
+pub const MAX_ACCELERATION: f32 = 60.;
+pub const COMFORTABLE_BRAKING: f32 = 120.;
+pub const MAX_BRAKING: f32 = 480.;
+// Gap kept to the car ahead when standing still
+pub const STANDSTILL_GAP: f32 = 4.;
+// Time it takes to drive up to where the car ahead is
+pub const TIME_HEADWAY: f32 = 0.25;
+// Below this speed a car counts as waiting
+pub const STOPPED_SPEED: f32 = 1.;
+// Emergency vehicles cruise at the top speed of the road
+pub const EMERGENCY_SPEED: f32 = MAX_SPEED;
 #[derive(Clone, Debug, PartialEq)]
 pub struct Car {
-    pub uuid: Uuid,
-    pub spawn_point: Vec2,
-    pub lifetime: Instant,
+    // Sequential per simulation, in spawn order
+    pub id: u64,
+    pub spawn_time: f32,
+    // Axis aligned bounds of the car body, see `body`
     pub car_rect: Rect,
-    pub current_direction: String,
+    // Centre of the car and its heading angle, 0 pointing east
+    pub position: Vec2,
+    pub heading: f32,
+    pub path: Arc<Path>,
+    // Distance driven along the path, the odometer
+    pub travelled: f32,
+    pub previous_travelled: f32,
+    // Speed actually driven over the last step, and its extremes
+    pub velocity: f32,
+    pub peak_speed: f32,
+    pub lowest_speed: f32,
+    // Simulated seconds spent inside the core intersection
+    pub time_in_intersection: f32,
+    // Simulated seconds spent standing still, and how often the car came
+    // to a stop
+    pub waiting_time: f32,
+    pub stops: u32,
+    // Close calls the car was part of
+    pub close_calls: u32,
+    // Simulated seconds the car waited in the entry queue of its lane
+    pub entry_delay: f32,
+    // The heading rounded to the nearest compass direction
+    pub current_direction: Direction,
     pub current_speed: f32,
     pub randomized_initial_speed: f32,
+    // Speed the intersection asked the car to approach the core at and the
+    // simulated time it is expected to reach it then, until it enters it
+    pub planned_speed: Option<f32>,
+    pub target_arrival: Option<f32>,
     pub radar: Rect,
     pub proximity: f32,
-    pub has_turned: bool,
-    pub behavior_code: String,
+    pub route: Route,
     pub waiting_flag: bool,
+    // Emergency vehicles get the right of way at the core, and cars on
+    // conflicting routes are held back by `yielding` until they are through
+    pub is_emergency: bool,
+    pub yielding: bool,
+    // Size and dynamics come from the class
+    pub class: VehicleClass,
     pub car_size: Dimensions,
-    pub radar_size: Dimensions,
-    pub dest_point: Vec2,
+    // How far ahead the radar reaches, see VehicleSpec::radar_range
+    pub radar_range: f32,
 }
 
 #[derive(Debug, PartialEq, Clone)]
 pub struct Dimensions {
     pub long_edge: f32,
     pub short_edge: f32,
-    pub delta_edge: f32,
 }
 impl Car {
-    pub fn new(randomized_behavior: &str, initial_direction: &str) -> Self {
-        let random_speed = gen_range(0.8, 2.);
-        let spawning = match randomized_behavior {
-            "RU" => vec2(1050., 495.),
-            "RL" => vec2(1050., 535.),
-            "RD" => vec2(1050., 574.),
-            "DU" => vec2(643., 1050.),
-            "DL" => vec2(603., 1050.),
-            "DR" => vec2(683., 1050.),
-            "LU" => vec2(150., 617.),
-            "LR" => vec2(150., 655.),
-            "LD" => vec2(150., 695.),
-            "UD" => vec2(516., 100.),
-            "UR" => vec2(558., 100.),
-            "UL" => vec2(477., 100.),
-            _ => panic!("Unexpected lane"),
-        };
+    // The cruising speed is capped at the top speed of the class
+    pub fn new(
+        lane: &Lane,
+        id: u64,
+        spawn_time: f32,
+        initial_speed: f32,
+        class: VehicleClass,
+    ) -> Self {
+        let spawning = lane.entry;
+        let initial_direction = lane.route.entry_heading();
+        let spec = class.spec();
+        let initial_speed = initial_speed.min(spec.max_speed);
+        let radar_range = spec.radar_range();
 
-        Car {
-            uuid: Uuid::new_v4(),
-            lifetime: Instant::now(),
-            spawn_point: spawning,
-            car_rect: if initial_direction == "West" || initial_direction == "East" {
-                Rect::new(spawning.x, spawning.y, CAR_SIZE.x, CAR_SIZE.y)
-            } else {
-                Rect::new(spawning.x, spawning.y, CAR_SIZE.y, CAR_SIZE.x)
-            },
+        let mut car = Car {
+            id,
+            spawn_time,
+            car_rect: Rect::new(spawning.x, spawning.y, CAR_SIZE.x, CAR_SIZE.y),
+            position: spawning,
+            heading: 0.,
+            path: lane.path.clone(),
+            travelled: 0.,
+            previous_travelled: 0.,
+            velocity: initial_speed,
+            peak_speed: 0.,
+            lowest_speed: f32::MAX,
+            time_in_intersection: 0.,
+            waiting_time: 0.,
+            stops: 0,
+            close_calls: 0,
+            entry_delay: 0.,
             radar: Rect::new(
-                spawning.x - RADAR_SIZE.x,
+                spawning.x - radar_range,
                 spawning.y,
-                RADAR_SIZE.x,
-                RADAR_SIZE.y,
+                radar_range,
+                spec.width,
             ),
-            proximity: RADAR_SIZE.x,
-            current_direction: initial_direction.to_string(),
-            randomized_initial_speed: random_speed,
-            current_speed: random_speed,
-            has_turned: false,
-            behavior_code: randomized_behavior.to_string(),
+            proximity: radar_range,
+            current_direction: initial_direction,
+            randomized_initial_speed: initial_speed,
+            planned_speed: None,
+            target_arrival: None,
+            current_speed: initial_speed,
+            route: lane.route,
             waiting_flag: false,
+            is_emergency: false,
+            yielding: false,
+            class,
+            car_size: spec.size(),
+            radar_range,
+        };
+        car.follow_path();
+        car
+    }
 
-            car_size: Dimensions {
-                long_edge: 43.,
-                short_edge: 33.,
-                delta_edge: CAR_SIZE.x - CAR_SIZE.y,
-            },
-            radar_size: Dimensions {
-                long_edge: 43.,
-                short_edge: 33.,
-                delta_edge: CAR_SIZE.x - CAR_SIZE.y,
-            },
-            dest_point: match randomized_behavior {
-                "RU" => vec2(683., 100.),
-                "RL" => vec2(100., 535.),
-                "RD" => vec2(555., 1050.),
-                "DU" => vec2(643., 100.),
-                "DL" => vec2(100., 574.),
-                "DR" => vec2(1057., 695.),
-                "LU" => vec2(593., 100.),
-                "LR" => vec2(1057., 655.),
-                "LD" => vec2(567., 1050.),
-                "UD" => vec2(516., 1050.),
-                "UR" => vec2(1057., 607.),
-                "UL" => vec2(100., 485.),
-                _ => panic!("Unexpected lane"),
-            },
-        }
+    // The car as it sits on the road, turned to its heading
+    pub fn body(&self) -> OrientedRect {
+        OrientedRect::new(
+            self.position,
+            self.car_size.long_edge,
+            self.car_size.short_edge,
+            self.heading,
+        )
+    }
+
+    // Places the car at `travelled` along its path
+    fn follow_path(&mut self) {
+        self.position = self.path.point_at(self.travelled);
+        self.heading = self.path.heading_at(self.travelled);
+        self.current_direction = Direction::from_heading(self.heading);
+        self.car_rect = self.body().bounding_rect();
+    }
+
+    // The body the car would have `distance` further along its path
+    pub fn body_after(&self, distance: f32) -> OrientedRect {
+        let travelled = self.travelled + distance;
+        OrientedRect::new(
+            self.path.point_at(travelled),
+            self.car_size.long_edge,
+            self.car_size.short_edge,
+            self.path.heading_at(travelled),
+        )
+    }
+
+    pub fn advance(&mut self, distance: f32) {
+        self.travelled += distance;
+        self.follow_path();
     }
 
+    // The new car needs the standstill gap plus its time headway around it,
+    // so it can brake for a car right in front. Cars pushed since the grid
+    // was built are checked directly, as they are not in it yet.
     pub fn spawn_if_can(
         cars_ref: &mut Vec<Car>,
-        randomized_behavior: &str,
-        initial_direction: &str,
-    ) {
-        let possible_new_car = Car::new(randomized_behavior, initial_direction);
-        if !cars_ref.iter_mut().any(|other_car| {
-            possible_new_car
-                .car_rect
-                .intersect(other_car.car_rect)
-                .is_some()
-        }) && cars_ref.len() < 9999
+        grid: &SpatialGrid,
+        lane: &Lane,
+        id: u64,
+        spawn_time: f32,
+        initial_speed: f32,
+        class: VehicleClass,
+    ) -> bool {
+        let possible_new_car = Car::new(lane, id, spawn_time, initial_speed, class);
+        let body = possible_new_car
+            .body()
+            .inflated(STANDSTILL_GAP + initial_speed * TIME_HEADWAY);
+        let area = grown(&body.bounding_rect(), MAX_SPEED * TIME_STEP);
+        let nearby = grid
+            .query(&area)
+            .into_iter()
+            .chain(grid.indexed()..cars_ref.len());
+        if !nearby
+            .map(|other_index| &cars_ref[other_index])
+            .any(|other_car| body.intersects(&other_car.body()))
+            && cars_ref.len() < 9999
         {
-            cars_ref.push(possible_new_car)
+            cars_ref.push(possible_new_car);
+            true
+        } else {
+            false
         }
     }
 
-    pub fn check_for_best_or_worst_time(&self, statistics: &mut Stats) {
-        let temp_time = self.lifetime.elapsed().as_secs_f32();
-        if temp_time < statistics.best_time {
-            statistics.best_time = temp_time;
-        }
+    pub fn check_for_best_or_worst_time(&self, now: f32, statistics: &mut Stats) {
+        let temp_time = now - self.spawn_time;
+        statistics.best_time = Some(
+            statistics
+                .best_time
+                .map_or(temp_time, |best_time| best_time.min(temp_time)),
+        );
         if temp_time > statistics.worst_time {
             statistics.worst_time = temp_time;
         }
-        let temp_velocity = self.spawn_point.distance(self.dest_point) / temp_time;
+        let temp_velocity = self.travelled / temp_time;
         if temp_velocity > statistics.best_velocity {
             statistics.best_velocity = temp_velocity;
         }
-        if temp_velocity < statistics.worst_velocity {
-            statistics.worst_velocity = temp_velocity;
+        statistics.worst_velocity = Some(
+            statistics
+                .worst_velocity
+                .map_or(temp_velocity, |worst_velocity| {
+                    worst_velocity.min(temp_velocity)
+                }),
+        );
+        if self.peak_speed > statistics.top_speed {
+            statistics.top_speed = self.peak_speed;
         }
     }
 
-    pub fn communicate_with_intersection(&mut self, cars_ref: &Vec<Car>, core_intersection: &Rect) {
-        let mut temp_cars = cars_ref.clone();
-        temp_cars.retain(|car| car.uuid != self.uuid);
-        if self.behavior_code == "LR"
-            && self.radar.intersect(*core_intersection).is_some()
-            && self.car_rect.intersect(*core_intersection).is_none()
-        {
-            self.waiting_flag = false;
-            if temp_cars.iter().any(|car| {
-                car.behavior_code == "LR" && car.car_rect.intersect(*core_intersection).is_some()
-            }) {
-                self.waiting_flag = true;
-            }
+    // Updates the trip measurements once the car has moved for the step
+    pub fn track_trip(&mut self, dt: f32, core_intersection: &Rect) {
+        let was_moving = self.velocity >= STOPPED_SPEED;
+        self.velocity = (self.travelled - self.previous_travelled) / dt;
+        self.previous_travelled = self.travelled;
+        self.peak_speed = self.peak_speed.max(self.velocity);
+        self.lowest_speed = self.lowest_speed.min(self.velocity);
+        if self.car_rect.intersect(*core_intersection).is_some() {
+            self.time_in_intersection += dt;
         }
-        if self.behavior_code == "LU"
-            && self.radar.intersect(*core_intersection).is_some()
-            && self.car_rect.intersect(*core_intersection).is_none()
-        {
-            self.waiting_flag = false;
-            if temp_cars.iter().any(|car| {
-                car.behavior_code == "LU" && car.car_rect.intersect(*core_intersection).is_some()
-            }) {
-                self.waiting_flag = true;
-            }
-        }
-        if self.behavior_code == "RD"
-            && self.radar.intersect(*core_intersection).is_some()
-            && self.car_rect.intersect(*core_intersection).is_none()
-        {
-            self.waiting_flag = false;
-            if temp_cars.iter().any(|car| {
-                car.behavior_code == "RD" && car.car_rect.intersect(*core_intersection).is_some()
-            }) {
-                self.waiting_flag = true;
-            }
-        }
-        if self.behavior_code == "RL"
-            && self.radar.intersect(*core_intersection).is_some()
-            && self.car_rect.intersect(*core_intersection).is_none()
-        {
-            self.waiting_flag = false;
-            if temp_cars.iter().any(|car| {
-                car.behavior_code == "RL" && car.car_rect.intersect(*core_intersection).is_some()
-            }) {
-                self.waiting_flag = true;
-            }
-        }
-
-        if self.behavior_code == "UR"
-            && self.radar.intersect(*core_intersection).is_some()
-            && self.car_rect.intersect(*core_intersection).is_none()
-        {
-            self.waiting_flag = false;
-            if temp_cars.iter().any(|car| {
-                (car.behavior_code == "UR" || car.behavior_code == "RL")
-                    && car.car_rect.intersect(*core_intersection).is_some()
-            }) {
-                self.waiting_flag = true;
-            }
-        }
-        if self.behavior_code == "UD"
-            && self.radar.intersect(*core_intersection).is_some()
-            && self.car_rect.intersect(*core_intersection).is_none()
-        {
-            self.waiting_flag = false;
-            if temp_cars.iter().any(|car| {
-                (car.behavior_code == "UD" || car.behavior_code == "RL")
-                    && car.car_rect.intersect(*core_intersection).is_some()
-            }) {
-                self.waiting_flag = true;
+        if self.velocity < STOPPED_SPEED {
+            self.waiting_time += dt;
+            if was_moving {
+                self.stops += 1;
             }
         }
+    }
 
-        if self.behavior_code == "DL"
-            && self.radar.intersect(*core_intersection).is_some()
-            && self.car_rect.intersect(*core_intersection).is_none()
-        {
-            self.waiting_flag = false;
-            if temp_cars.iter().any(|car| {
-                (car.behavior_code == "DL" || car.behavior_code == "UR")
-                    && car.car_rect.intersect(*core_intersection).is_some()
-            }) {
-                self.waiting_flag = true;
-            }
-        }
-        if self.behavior_code == "DU"
-            && self.radar.intersect(*core_intersection).is_some()
-            && self.car_rect.intersect(*core_intersection).is_none()
-        {
-            self.waiting_flag = false;
-            if temp_cars.iter().any(|car| {
-                (car.behavior_code == "DU" || car.behavior_code == "LR")
-                    && car.car_rect.intersect(*core_intersection).is_some()
-            }) {
-                self.waiting_flag = true;
-            }
+    pub fn has_left_the_road(&self, despawn: &DespawnLines) -> bool {
+        match self.current_direction {
+            Direction::West => self.car_rect.x < despawn.west,
+            Direction::North => self.car_rect.y < despawn.north,
+            Direction::South => self.car_rect.y > despawn.south,
+            Direction::East => self.car_rect.x + self.car_size.long_edge > despawn.east,
         }
     }
 
-    pub fn check_for_collision(&self, temp_cars: &mut Vec<Car>, statistics: &mut Stats) {
-        temp_cars.retain(|temp_car| temp_car.uuid != self.uuid);
-        if temp_cars
-            .iter()
-            .any(|temp_car| temp_car.car_rect.intersect(self.car_rect).is_some())
-        {
+    pub fn check_for_collision(
+        &self,
+        car_index: usize,
+        cars: &[Car],
+        grid: &SpatialGrid,
+        statistics: &mut Stats,
+    ) {
+        let body = self.body();
+        if grid.query(&self.car_rect).into_iter().any(|other_index| {
+            other_index != car_index && cars[other_index].body().intersects(&body)
+        }) {
             statistics.collisions += 1;
         }
     }
 
+    // Moves the car along its path unless that would run it into another
+    // car. Cars earlier in the list have already moved this step, so the
+    // grid is searched a step's worth of travel around the new body.
     pub fn move_one_step_if_no_collide(
-        &mut self,
-        temp_cars: &mut Vec<Car>,
-        statistics: &mut Stats,
+        cars: &mut [Car],
+        car_index: usize,
+        grid: &SpatialGrid,
+        dt: f32,
     ) {
-        let mut temp_self_car = self.clone();
-        temp_cars.retain(|car| temp_self_car.uuid != car.uuid);
-
-        match &*self.current_direction {
-            "West" => {
-                temp_self_car.car_rect.x -= temp_self_car.current_speed;
-                if temp_cars
-                    .iter_mut()
-                    .all(|car| temp_self_car.car_rect.intersect(car.car_rect).is_none())
-                {
-                    temp_cars.push(temp_self_car);
-                    self.car_rect.x -= self.current_speed;
-                } else {
-                    statistics.close_calls += 1;
-                }
-            }
-            "North" => {
-                temp_self_car.car_rect.y -= temp_self_car.current_speed;
-                if temp_cars
-                    .iter_mut()
-                    .all(|car| temp_self_car.car_rect.intersect(car.car_rect).is_none())
-                {
-                    temp_cars.push(temp_self_car);
-                    self.car_rect.y -= self.current_speed;
-                } else {
-                    statistics.close_calls += 1;
-                }
-            }
-            "South" => {
-                temp_self_car.car_rect.y += temp_self_car.current_speed;
-                if temp_cars
-                    .iter_mut()
-                    .all(|car| temp_self_car.car_rect.intersect(car.car_rect).is_none())
-                {
-                    temp_cars.push(temp_self_car);
-                    self.car_rect.y += self.current_speed;
-                } else {
-                    statistics.close_calls += 1;
-                }
-            }
-            "East" => {
-                temp_self_car.car_rect.x += temp_self_car.current_speed;
-                if temp_cars
-                    .iter_mut()
-                    .all(|car| temp_self_car.car_rect.intersect(car.car_rect).is_none())
-                {
-                    temp_cars.push(temp_self_car);
-                    self.car_rect.x += self.current_speed;
-                } else {
-                    statistics.close_calls += 1;
-                }
-            }
-            _ => {}
-        };
-    }
-
-    pub fn update_radar(&mut self, car_index: usize, temp_cars: &Vec<Car>) {
-        match &*self.current_direction {
-            "West" => {
-                // Update radar rectangle
-                (self.radar.x, self.radar.y) =
-                    (self.car_rect.x - self.radar_size.long_edge, self.car_rect.y);
-                (self.radar.w, self.radar.h) =
-                    (self.radar_size.long_edge, self.radar_size.short_edge);
-
-                // Reposition the radar when intersection occur
-                for (other_index, other_car) in temp_cars.iter().enumerate() {
-                    if car_index != other_index
-                        && self.radar.intersect(other_car.car_rect).is_some()
-                    {
-                        self.radar.x = other_car.car_rect.x + other_car.car_rect.w;
-                    }
-                    // Update radar width
-                    self.radar.w = (self.car_rect.x - self.radar.x).abs().min(43.);
-                }
-            }
-            "North" => {
-                // Update radar rectangle
-                (self.radar.x, self.radar.y) =
-                    (self.car_rect.x, self.car_rect.y - self.radar_size.long_edge);
-                //Reposition the radar when intersection occur
-                for (other_index, other_car) in temp_cars.iter().enumerate() {
-                    if car_index != other_index
-                        && (self.radar.intersect(other_car.car_rect).is_some())
-                    {
-                        self.radar.y = other_car.car_rect.y + other_car.car_rect.h;
-                    }
-                    // Update radar width
-                    self.radar.h = (self.car_rect.y - self.radar.y).abs().min(43.);
-                    self.radar.w = 33.;
-                }
-            }
-            "South" => {
-                // Update radar rectangle
-
-                (self.radar.x, self.radar.y) =
-                    (self.car_rect.x, self.car_rect.y + self.radar_size.long_edge);
-                (self.radar.w, self.radar.h) =
-                    (self.radar_size.short_edge, self.radar_size.long_edge);
-                for (other_index, other_car) in temp_cars.iter().enumerate() {
-                    if car_index != other_index
-                        && self.radar.intersect(other_car.car_rect).is_some()
-                    {
-                        //self.radar.h = vec2(self.radar.x, self.radar.y).distance(vec2(other_car.car_rect.x, other_car.car_rect.y)).min(self.radar_size.long_edge)
-                        self.radar.h =
-                            other_car.car_rect.y - (self.car_rect.y + self.car_size.long_edge)
-                    }
-                }
-            }
-            "East" => {
-                // Update radar rectangle
-                (self.radar.x, self.radar.y) = (self.car_rect.x + self.car_rect.w, self.car_rect.y);
-                (self.radar.w, self.radar.h) =
-                    (self.radar_size.long_edge, self.radar_size.short_edge);
-
-                for (other_index, other_car) in temp_cars.iter().enumerate() {
-                    if car_index != other_index
-                        && self.radar.intersect(other_car.car_rect).is_some()
-                    {
-                        //self.radar.y = other_car.car_rect.y + other_car.car_rect.h;
-                        self.radar.w = other_car.car_rect.x - (self.car_rect.x + self.car_rect.w);
-                    }
-                    if self.uuid != other_car.uuid
-                        && self.radar.intersect(other_car.radar).is_some()
-                        && self.car_rect.intersect(other_car.radar).is_none()
-                        && other_car.current_direction != "North"
-                    {
-                        self.radar.w = other_car.car_rect.x - (self.car_rect.x + self.car_rect.w);
-                    }
-                }
-            }
-            _ => {}
+        let distance = cars[car_index].current_speed * dt;
+        let body = cars[car_index].body_after(distance);
+        let area = grown(&body.bounding_rect(), MAX_SPEED * dt);
+        if grid.query(&area).into_iter().all(|other_index| {
+            other_index == car_index || !body.intersects(&cars[other_index].body())
+        }) {
+            cars[car_index].advance(distance);
         }
     }
 
-    pub fn adjust_current_speed(&mut self) {
-        if &*self.current_direction == "West" || &*self.current_direction == "East" {
-            match self.radar.w {
-                //radar_width if radar_width <= 4. => self.current_speed = 0.,
-                radar_width if radar_width <= 3. => {
-                    self.current_speed = self.randomized_initial_speed * 0.;
-                }
-                radar_width if radar_width <= 30. => {
-                    self.current_speed = self.randomized_initial_speed * 0.25;
-                }
-                radar_width if radar_width <= 39. => {
-                    self.current_speed = self.randomized_initial_speed * 0.50
-                }
-                _ => self.current_speed = self.randomized_initial_speed,
-            }
-        } else if &*self.current_direction == "North" || &*self.current_direction == "South" {
-            match self.radar.h {
-                //radar_height if radar_height <= 4. => self.current_speed = 0.,
-                radar_height if radar_height <= 3. => {
-                    self.current_speed = 0.;
-                }
-                radar_height if radar_height <= 20. => {
-                    self.current_speed = self.randomized_initial_speed * 0.25;
-                }
-                radar_height if radar_height <= 39. => {
-                    self.current_speed = self.randomized_initial_speed * 0.50;
-                }
-                _ => self.current_speed = self.randomized_initial_speed,
-            }
-        }
+    // The bodies the car would have on each of the following steps when
+    // driving undisturbed at its cruising speed, the planned one until it
+    // enters `area`, up to the step it has crossed `area`. Empty if its
+    // path misses the area, None if it cannot cross within `max_steps`.
+    pub fn predict_crossing(
+        &self,
+        dt: f32,
+        area: &Rect,
+        max_steps: usize,
+    ) -> Option<Vec<OrientedRect>> {
+        self.predict_crossing_until(dt, area, max_steps, |_, _| false)
     }
 
-    pub fn turn_if_can(&mut self, temp_cars: &Vec<Car>) {
-        if !self.has_turned && self.behavior_code == "RU" && self.car_rect.x <= 683. {
-            self.waiting_flag = true;
-            let mut clear_to_turn = true;
-            let temp_rect = Rect::new(
-                683.,
-                self.car_rect.y - (self.car_rect.w - self.car_rect.h).abs(),
-                self.car_rect.h,
-                self.car_rect.w,
-            );
-            for other_car in temp_cars {
-                if self.uuid != other_car.uuid
-                    && (temp_rect.intersect(other_car.car_rect).is_some()
-                        || temp_rect.intersect(other_car.car_rect).is_some())
-                {
-                    clear_to_turn = false;
-                }
-            }
-            if clear_to_turn {
-                self.car_rect = temp_rect;
-                self.waiting_flag = false;
-                self.current_direction = "North".to_string();
-                self.has_turned = true;
-            }
+    // predict_crossing giving up with None as soon as `is_blocked` has said
+    // the body on some step, counted from 0, cannot be had and the car has
+    // entered `area`. A path that misses the area is never blocked.
+    pub fn predict_crossing_until(
+        &self,
+        dt: f32,
+        area: &Rect,
+        max_steps: usize,
+        mut is_blocked: impl FnMut(usize, &OrientedRect) -> bool,
+    ) -> Option<Vec<OrientedRect>> {
+        let mut ghost = self.clone();
+        let mut footprints = Vec::new();
+        let mut has_entered = false;
+        let mut was_blocked = false;
+        for _ in 0..max_steps {
+            ghost.current_speed = (ghost.current_speed
+                + ghost
+                    .free_road_acceleration()
+                    .max(-ghost.class.spec().max_braking)
+                    * dt)
+                .clamp(0., ghost.randomized_initial_speed);
+            ghost.advance(ghost.current_speed * dt);
+            if ghost.car_rect.intersect(*area).is_some() {
+                has_entered = true;
+                ghost.planned_speed = None;
+            } else if has_entered {
+                return Some(footprints);
+            }
+            let footprint = ghost.body();
+            was_blocked = was_blocked || is_blocked(footprints.len(), &footprint);
+            if was_blocked && has_entered {
+                return None;
+            }
+            footprints.push(footprint);
         }
-        if !self.has_turned && self.behavior_code == "RD" && self.car_rect.x <= 555. {
-            self.waiting_flag = true;
-            let mut clear_to_turn = true;
-            let temp_rect = Rect::new(555., self.car_rect.y, self.car_rect.h, self.car_rect.w);
-            for other_car in temp_cars {
-                if self.uuid != other_car.uuid
-                    && (temp_rect.intersect(other_car.car_rect).is_some()
-                        || temp_rect.intersect(other_car.car_rect).is_some())
-                {
-                    clear_to_turn = false;
-                }
-            }
-            if clear_to_turn {
-                self.car_rect = temp_rect;
-                self.waiting_flag = false;
-                self.current_direction = "South".to_string();
-                self.has_turned = true;
-            }
+        if has_entered {
+            None
+        } else {
+            Some(Vec::new())
         }
-        if !self.has_turned && self.behavior_code == "DR" && self.car_rect.y <= 695. {
-            self.waiting_flag = true;
-            let mut clear_to_turn = true;
-            let temp_rect = Rect::new(self.car_rect.x, 695., self.car_rect.h, self.car_rect.w);
-            for other_car in temp_cars {
-                if self.uuid != other_car.uuid
-                    && (temp_rect.intersect(other_car.car_rect).is_some()
-                        || temp_rect.intersect(other_car.car_rect).is_some())
-                {
-                    clear_to_turn = false;
-                }
-            }
-            if clear_to_turn {
-                self.car_rect = temp_rect;
-                self.waiting_flag = false;
-                self.current_direction = "East".to_string();
-                self.has_turned = true;
-            }
-        }
-        if !self.has_turned && self.behavior_code == "DL" && self.car_rect.y <= 574. {
-            self.waiting_flag = true;
-            let mut clear_to_turn = true;
-            let temp_rect = Rect::new(
-                self.car_rect.x - (self.car_rect.h - self.car_rect.w).abs(),
-                574.,
-                self.car_rect.h,
-                self.car_rect.w,
-            );
-            for other_car in temp_cars {
-                if self.uuid != other_car.uuid
-                    && (temp_rect.intersect(other_car.car_rect).is_some()
-                        || temp_rect.intersect(other_car.car_rect).is_some())
-                {
-                    clear_to_turn = false;
-                }
-            }
-            if clear_to_turn {
-                self.car_rect = temp_rect;
-                self.waiting_flag = false;
-                self.current_direction = "West".to_string();
-                self.has_turned = true;
-            }
-        }
-        if !self.has_turned
-            && self.behavior_code == "LD"
-            && self.car_rect.x + self.car_size.long_edge >= 510.
-        {
-            self.waiting_flag = true;
-            let temp_rect = Rect::new(
-                510. - (self.car_size.long_edge - self.car_size.delta_edge),
-                self.car_rect.y,
-                self.car_size.short_edge,
-                self.car_size.long_edge,
-            );
-            self.car_rect = temp_rect;
-            self.waiting_flag = false;
-            self.current_direction = "South".to_string();
-            self.has_turned = true;
+    }
+
+    // The radar rectangle for this step, reaching `radar_range` ahead of the
+    // front of the car and shortened up to the nearest car ahead. Cars
+    // heading east also stop short of the radar of a car crossing their way,
+    // where the two meet within a vehicle length ahead of either car.
+    // Only the cars the grid places near the radar are looked at.
+    pub fn scan_radar(&self, car_index: usize, cars: &[Car], grid: &SpatialGrid) -> Rect {
+        let body = self.car_rect;
+        let range = self.radar_range;
+        let direction = self.current_direction;
+        // As wide as the body, which grows across the way while it turns,
+        // but never wider than the vehicle so turns don't block the next lane
+        let width = match direction {
+            Direction::West | Direction::East => body.h,
+            Direction::North | Direction::South => body.w,
         }
-        if !self.has_turned
-            && self.behavior_code == "LU"
-            && self.car_rect.x + self.car_size.delta_edge >= 603.
-        {
-            self.waiting_flag = true;
-            let mut clear_to_turn = true;
-            let temp_rect = Rect::new(
-                603.,
-                self.car_rect.y - self.car_size.delta_edge,
-                self.car_size.short_edge,
-                self.car_size.long_edge,
-            );
-            for other_car in temp_cars {
-                if self.uuid != other_car.uuid && temp_rect.intersect(other_car.car_rect).is_some()
-                {
-                    clear_to_turn = false;
+        .min(self.car_size.short_edge);
+        let front = match direction {
+            Direction::West => body.x,
+            Direction::East => body.right(),
+            Direction::North => body.y,
+            Direction::South => body.bottom(),
+        };
+        let radar_of = |length: f32| match direction {
+            Direction::West => Rect::new(front - length, body.y, length, width),
+            Direction::East => Rect::new(front, body.y, length, width),
+            Direction::North => Rect::new(body.x, front - length, width, length),
+            Direction::South => Rect::new(body.x, front, width, length),
+        };
+        // Distance from the front of the car to a rectangle ahead of it, 0
+        // if the rectangle reaches back past the front, None if it is not
+        // ahead at all
+        let gap_to = |rect: Rect| match direction {
+            Direction::West => (rect.x < front).then(|| (front - rect.right()).max(0.)),
+            Direction::East => (rect.right() > front).then(|| (rect.x - front).max(0.)),
+            Direction::North => (rect.y < front).then(|| (front - rect.bottom()).max(0.)),
+            Direction::South => (rect.bottom() > front).then(|| (rect.y - front).max(0.)),
+        };
+
+        let radar = radar_of(range);
+        let near_radar = radar_of(range.min(self.car_size.long_edge));
+        let mut gap = range;
+        for other_index in grid.query(&radar) {
+            let other_car = &cars[other_index];
+            if other_index == car_index {
+                continue;
+            }
+            let is_seen = radar.intersect(other_car.car_rect).is_some();
+            let crossing_way = other_car.radar_ahead(other_car.car_size.long_edge);
+            let is_crossing = direction == Direction::East
+                && near_radar.intersect(crossing_way).is_some()
+                && body.intersect(crossing_way).is_none()
+                && other_car.current_direction != Direction::North;
+            if is_seen || is_crossing {
+                if let Some(other_gap) = gap_to(other_car.car_rect) {
+                    gap = gap.min(other_gap);
                 }
             }
-            if clear_to_turn {
-                self.car_rect = temp_rect;
-                self.waiting_flag = false;
-                self.current_direction = "North".to_string();
-                self.has_turned = true;
-            }
         }
-        if !self.has_turned
-            && self.behavior_code == "UL"
-            && self.car_rect.y + self.car_size.long_edge >= 528.
-        {
-            self.waiting_flag = true;
-            let temp_rect = Rect::new(
-                self.car_rect.x - self.car_size.delta_edge,
-                528. - (self.car_size.long_edge - self.car_size.delta_edge),
-                self.car_size.long_edge,
-                self.car_size.short_edge,
-            );
+        radar_of(gap)
+    }
 
-            self.car_rect = temp_rect;
-            self.waiting_flag = false;
-            self.current_direction = "West".to_string();
-            self.has_turned = true;
+    // The part of the radar within `length` of the front of the car
+    fn radar_ahead(&self, length: f32) -> Rect {
+        let radar = self.radar;
+        match self.current_direction {
+            Direction::West => {
+                let length = length.min(radar.w);
+                Rect::new(radar.right() - length, radar.y, length, radar.h)
+            }
+            Direction::East => Rect::new(radar.x, radar.y, length.min(radar.w), radar.h),
+            Direction::North => {
+                let length = length.min(radar.h);
+                Rect::new(radar.x, radar.bottom() - length, radar.w, length)
+            }
+            Direction::South => Rect::new(radar.x, radar.y, radar.w, length.min(radar.h)),
         }
-        if !self.has_turned
-            && self.behavior_code == "UR"
-            && self.car_rect.y + self.car_size.long_edge >= 650.
-        {
-            self.waiting_flag = true;
-            let mut clear_to_turn = true;
-            let temp_rect = Rect::new(
-                self.car_rect.x,
-                650. - (self.car_size.long_edge - self.car_size.delta_edge),
-                self.car_size.long_edge,
-                self.car_size.short_edge,
-            );
-            for other_car in temp_cars {
-                if self.uuid != other_car.uuid
-                    && (temp_rect.intersect(other_car.car_rect).is_some()
-                        || (temp_rect.intersect(other_car.radar).is_some()
-                            && other_car.behavior_code == "DL"))
-                {
-                    clear_to_turn = false;
-                }
-            }
-            if clear_to_turn {
-                self.car_rect = temp_rect;
-                self.waiting_flag = false;
-                self.current_direction = "East".to_string();
-                self.has_turned = true;
-            }
+    }
+
+    // Whether the intersection or an emergency vehicle stops the car
+    pub fn is_held(&self) -> bool {
+        self.waiting_flag || self.yielding
+    }
+
+    // The speed the car settles at on a free road
+    pub fn cruising_speed(&self) -> f32 {
+        self.planned_speed
+            .unwrap_or(self.randomized_initial_speed)
+            .min(self.randomized_initial_speed)
+    }
+
+    pub fn free_road_acceleration(&self) -> f32 {
+        self.class.spec().acceleration * (1. - (self.current_speed / self.cruising_speed()).powi(4))
+    }
+
+    // Intelligent Driver Model acceleration behind an obstacle `gap` ahead
+    // that the car is closing in on at `closing_speed`
+    pub fn following_acceleration(&self, gap: f32, closing_speed: f32) -> f32 {
+        let spec = self.class.spec();
+        let desired_gap = STANDSTILL_GAP
+            + self.current_speed * TIME_HEADWAY
+            + self.current_speed * closing_speed / (2. * (spec.acceleration * spec.braking).sqrt());
+        self.free_road_acceleration()
+            - spec.acceleration * (desired_gap.max(0.) / gap.max(0.1)).powi(2)
+    }
+
+    // Speeds the car up or slows it down depending on the gap its radar
+    // measures, the gap of the previous step is kept in `proximity` to tell
+    // how fast the car closes in on whatever is ahead
+    pub fn adjust_current_speed(&mut self, dt: f32) {
+        let gap = match self.current_direction {
+            Direction::West | Direction::East => self.radar.w,
+            Direction::North | Direction::South => self.radar.h,
+        };
+        let closing_speed = ((self.proximity - gap) / dt).clamp(-MAX_SPEED, self.current_speed);
+        self.proximity = gap;
+
+        if self.is_held() {
+            self.current_speed = 0.;
+            return;
         }
+        let acceleration = if gap >= self.radar_range {
+            self.free_road_acceleration()
+        } else {
+            self.following_acceleration(gap, closing_speed)
+        };
+        // Whatever the model says, never close in further than the standstill gap
+        let speed_limit = (gap - STANDSTILL_GAP).max(0.) / dt;
+        let max_braking = self.class.spec().max_braking;
+        self.current_speed = (self.current_speed + acceleration.max(-max_braking) * dt)
+            .clamp(0., self.randomized_initial_speed)
+            .min(speed_limit);
     }
 
-    pub fn draw_all_components(&self, car_texture: &Texture2D, debug: bool) {
+    pub fn draw_all_components(&self, sprites: &Sprites, debug: bool) {
         if debug {
             // Draw Radar Rect
             draw_rectangle(
@@ -601,84 +493,115 @@ impl Car {
                 Color::new(1.0, 0.0, 0.0, 0.1),
             );
 
-            // Draw Car Rect
-            draw_rectangle(
+            // Draw the path and the car body
+            self.path.draw(1., Color::new(0.0, 0.0, 1.0, 0.2));
+            self.body().draw(Color::new(0.0, 1.0, 0.0, 0.3));
+            // The speed, and the plan of the intersection if there is one
+            let label = match (self.planned_speed, self.target_arrival) {
+                (Some(speed), Some(arrival)) => {
+                    format!("{:.0} > {:.0} @{:.1}s", self.velocity, speed, arrival)
+                }
+                _ => format!("{:.0}", self.velocity),
+            };
+            draw_text(
+                label.as_str(),
                 self.car_rect.x,
-                self.car_rect.y,
-                self.car_rect.w,
-                self.car_rect.h,
-                Color::new(0.0, 1.0, 0.0, 0.3),
+                self.car_rect.y - 2.,
+                18.,
+                BLACK,
             );
         }
 
-        // Draw Car image top of rect
-        match &*self.current_direction {
-            "West" => draw_texture_ex(
-                car_texture,
-                self.car_rect.x + 1.5,
-                self.car_rect.y + 1.5,
-                WHITE,
-                DrawTextureParams {
-                    dest_size: Some(vec2(40., 30.)),
-                    source: None,
-                    rotation: 0.,
-                    flip_x: false,
-                    flip_y: false,
-                    pivot: None,
-                },
-            ),
-            "North" => {
-                let degree: f32 = 90.;
-                draw_texture_ex(
-                    car_texture,
-                    self.car_rect.x - 3.,
-                    self.car_rect.y + 7.,
-                    WHITE,
-                    DrawTextureParams {
-                        dest_size: Some(vec2(40., 30.)),
-                        source: None,
-                        rotation: degree.to_radians(),
-                        flip_x: false,
-                        flip_y: false,
-                        pivot: None,
-                    },
-                )
-            }
-            "South" => {
-                let degree: f32 = 270.;
-                draw_texture_ex(
-                    car_texture,
-                    self.car_rect.x - 3.,
-                    self.car_rect.y + 7.,
-                    WHITE,
-                    DrawTextureParams {
-                        dest_size: Some(vec2(40., 30.)),
-                        source: None,
-                        rotation: degree.to_radians(),
-                        flip_x: false,
-                        flip_y: false,
-                        pivot: None,
-                    },
-                )
-            }
-            "East" => {
-                let degree: f32 = 180.;
-                draw_texture_ex(
-                    car_texture,
-                    self.car_rect.x + 2.,
-                    self.car_rect.y + 2.,
-                    WHITE,
-                    DrawTextureParams {
-                        dest_size: Some(vec2(40., 30.)),
-                        source: None,
-                        rotation: degree.to_radians(),
-                        flip_x: false,
-                        flip_y: false,
-                        pivot: None,
-                    },
-                )
-            }
-            _ => {}
+        draw_car_texture(
+            sprites.for_vehicle(self.class, self.is_emergency),
+            self.position,
+            self.heading,
+            self.class.sprite_size(),
+        );
+    }
+}
+
+// The images vehicles are drawn with, one per class and one for the
+// emergency vehicles
+pub struct Sprites {
+    pub classes: HashMap<VehicleClass, Texture2D>,
+    pub emergency: Texture2D,
+}
+
+impl Sprites {
+    pub async fn load() -> Self {
+        let mut classes = HashMap::new();
+        for class in VehicleClass::ALL {
+            let texture = load_texture(&class.sprite_path()).await.unwrap();
+            classes.insert(class, texture);
+        }
+        Sprites {
+            classes,
+            emergency: load_texture("assets/emergency.png").await.unwrap(),
         }
     }
+
+    pub fn for_vehicle(&self, class: VehicleClass, is_emergency: bool) -> &Texture2D {
+        if is_emergency {
+            &self.emergency
+        } else {
+            &self.classes[&class]
+        }
+    }
+}
+
+// Draws a vehicle image of `size` centred on `position`, the texture
+// itself faces west
+pub fn draw_car_texture(car_texture: &Texture2D, position: Vec2, heading: f32, size: Vec2) {
+    draw_texture_ex(
+        car_texture,
+        position.x - size.x / 2.,
+        position.y - size.y / 2.,
+        WHITE,
+        DrawTextureParams {
+            dest_size: Some(size),
+            source: None,
+            rotation: heading - PI,
+            flip_x: false,
+            flip_y: false,
+            pivot: None,
+        },
+    );
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // A motorcycle queued between a held car and the car behind it must
+    // measure its gap to the car in front, not to the one behind
+    #[test]
+    fn short_vehicle_sees_the_vehicle_ahead() {
+        let layout = Layout::load("assets/intersection.toml").unwrap();
+        let lane = layout
+            .lane(Route::new(Approach::Right, Turn::Straight))
+            .unwrap();
+        let mut held = Car::new(lane, 0, 0., 0., VehicleClass::Car);
+        let mut motorcycle = Car::new(lane, 1, 0., 0., VehicleClass::Motorcycle);
+        let mut behind = Car::new(lane, 2, 0., 0., VehicleClass::Car);
+        held.advance(200.);
+        motorcycle
+            .advance(200. - (CAR_SIZE.x + motorcycle.car_size.long_edge) / 2. - STANDSTILL_GAP);
+        behind.advance(
+            motorcycle.travelled - (CAR_SIZE.x + motorcycle.car_size.long_edge) / 2. - 10.,
+        );
+        let cars = vec![held, motorcycle, behind];
+        let mut grid = SpatialGrid::new();
+        grid.rebuild(&cars);
+
+        let radar = cars[1].scan_radar(1, &cars, &grid);
+        let gap = cars[1].car_rect.x - cars[0].car_rect.right();
+        assert!(
+            (radar.w - gap).abs() < 0.01,
+            "radar {} gap {}",
+            radar.w,
+            gap
+        );
+        assert!((gap - STANDSTILL_GAP).abs() < 0.01);
+    }
 }
@@ -0,0 +1,266 @@
+use crate::arrivals::*;
+use crate::intersection::*;
+use crate::route::*;
+use crate::signals::*;
+use crate::simulation::*;
+use crate::vehicles::*;
+
+pub const USAGE: &str = "\
+Usage: smart-road [options]
+
+Options:
+  --headless                Run without a window and report the stats on exit
+  --duration <seconds>      Stop after this many simulated seconds
+  --vehicles <count>        Stop once this many cars have left the road
+  --seed <n>                Seed of the run, taken from the clock if not given
+  --arrivals <file>         Let cars arrive as set by a demand file, see
+                            assets/demand/
+  --rate [<approach>=]<n>   Cars per minute arriving from an approach (U, D,
+                            L or R), or from every approach; repeatable, and
+                            overrides the rate of a demand file
+  --classes <mix>           Share of each vehicle class among the spawned
+                            vehicles, e.g. car=6,bus=1; classes left out
+                            get none, overrides the mix of a demand file
+                            [default: car=70,van=10,bus=5,truck=5,
+                            motorcycle=10]
+  --scenario <file>         Play back the spawns of a scenario file, running
+                            for its duration unless --duration is given
+  --random                  Start with random spawning on, as with the R key
+  --controller <name>       Intersection controller: reservation, fcfs,
+                            batch, longest-queue, rules, fixed-time or
+                            actuated [default: reservation]
+  --signals <file>          Signal plan of the fixed-time and actuated
+                            controllers, one phase per approach if not given
+  --safety-distance <px>    Distance below which two cars have a close call
+  --layout <file>           Layout file [default: assets/intersection.toml]
+  --output <file>           Write the stats to a file instead of stdout, as
+                            JSON for a .json file
+  --trips <file>            Write the trip of every car that left the road,
+                            as JSON for a .json file and as CSV otherwise
+  --record <file>           Record the spawns and the state of every step
+                            to a JSON Lines file as the run goes
+  --replay <file>           Play back a recording in the window instead of
+                            running a simulation
+  --help                    Show this message";
+
+// Options of a run, taken from the command line
+#[derive(Debug, Clone, PartialEq)]
+pub struct Options {
+    pub headless: bool,
+    pub duration: Option<f32>,
+    pub vehicles: Option<u32>,
+    pub seed: Option<u64>,
+    pub random: bool,
+    pub scenario: Option<String>,
+    pub layout: String,
+    pub output: Option<String>,
+    pub trips: Option<String>,
+    pub record: Option<String>,
+    pub replay: Option<String>,
+    pub help: bool,
+    // Everything of the config but the seed, which is settled in main
+    pub config: SimulationConfig,
+}
+
+impl Default for Options {
+    fn default() -> Self {
+        Options {
+            headless: false,
+            duration: None,
+            vehicles: None,
+            seed: None,
+            random: false,
+            scenario: None,
+            layout: String::from("assets/intersection.toml"),
+            output: None,
+            trips: None,
+            record: None,
+            replay: None,
+            help: false,
+            config: SimulationConfig::default(),
+        }
+    }
+}
+
+impl Options {
+    pub fn parse(args: impl IntoIterator<Item = String>) -> Result<Self, String> {
+        let mut options = Options::default();
+        let mut arrivals = None;
+        let mut rates = Vec::new();
+        let mut classes = None;
+        let mut args = args.into_iter();
+        while let Some(arg) = args.next() {
+            let mut value = || args.next().ok_or(format!("{} needs a value", arg));
+            match arg.as_str() {
+                "--headless" => options.headless = true,
+                "--duration" => options.duration = Some(number(&arg, value()?)?),
+                "--vehicles" => options.vehicles = Some(number(&arg, value()?)?),
+                "--seed" => options.seed = Some(number(&arg, value()?)?),
+                "--arrivals" => arrivals = Some(value()?),
+                "--rate" => rates.push(value()?),
+                "--classes" => classes = Some(value()?),
+                "--random" => options.random = true,
+                "--scenario" => options.scenario = Some(value()?),
+                "--controller" => options.config.controller = Controller::try_from(value()?)?,
+                "--signals" => options.config.signals = Some(SignalPlan::load(&value()?)?),
+                "--safety-distance" => {
+                    options.config.safety_distance = number(&arg, value()?)?;
+                }
+                "--layout" => options.layout = value()?,
+                "--output" => options.output = Some(value()?),
+                "--trips" => options.trips = Some(value()?),
+                "--record" => options.record = Some(value()?),
+                "--replay" => options.replay = Some(value()?),
+                "--help" => options.help = true,
+                _ => return Err(format!("unknown option {}", arg)),
+            }
+        }
+
+        // --rate and --classes go on top of the demand file, wherever they
+        // are on the command line
+        if let Some(path) = arrivals {
+            options.config.demand = Demand::load(&path)?;
+            options.random = true;
+        }
+        for rate in rates {
+            options.parse_rate(rate)?;
+        }
+        if let Some(classes) = classes {
+            options.parse_classes(classes)?;
+        }
+
+        if options.replay.is_some() && options.headless {
+            return Err(String::from("--replay needs the window, not --headless"));
+        }
+        let has_arrivals = options.random || options.scenario.is_some();
+        // A scenario may bring its own duration, which is only known once
+        // it is loaded
+        if options.headless
+            && options.duration.is_none()
+            && options.vehicles.is_none()
+            && options.scenario.is_none()
+        {
+            return Err(String::from("--headless needs --duration or --vehicles"));
+        }
+        if options.vehicles.is_some() && options.duration.is_none() && !has_arrivals {
+            return Err(String::from(
+                "--vehicles needs --rate or --random to ever finish",
+            ));
+        }
+        Ok(options)
+    }
+
+    // "U=12" sets the rate of one approach, "12" the rate of all of them
+    fn parse_rate(&mut self, value: String) -> Result<(), String> {
+        let (approaches, rate) = match value.split_once('=') {
+            Some((letter, rate)) => (vec![Approach::try_from(letter.to_string())?], rate),
+            None => (Approach::ALL.to_vec(), value.as_str()),
+        };
+        let rate: f32 = number("--rate", rate.to_string())?;
+        if !rate.is_finite() || rate < 0. {
+            return Err(format!(
+                "the rate of --rate must be a finite number of at least 0, not {}",
+                rate
+            ));
+        }
+        let demand = &mut self.config.demand;
+        for approach in approaches {
+            match demand
+                .approaches
+                .iter_mut()
+                .find(|demand| demand.approach == approach)
+            {
+                Some(demand) => demand.rate = rate,
+                None => demand.approaches.push(ApproachDemand {
+                    approach,
+                    rate,
+                    turns: TurnWeights::default(),
+                }),
+            }
+        }
+        self.random = true;
+        Ok(())
+    }
+
+    // "car=6,bus=1" gives those classes their weights and none to the rest
+    fn parse_classes(&mut self, value: String) -> Result<(), String> {
+        let mut classes = ClassMix::empty();
+        for share in value.split(',') {
+            let Some((name, weight)) = share.split_once('=') else {
+                return Err(format!("invalid class share {:?} for --classes", share));
+            };
+            let class = VehicleClass::try_from(name.to_string())?;
+            let weight: f32 = number("--classes", weight.to_string())?;
+            if weight < 0. {
+                return Err(format!("the share of {} must not be negative", name));
+            }
+            *classes.weight_mut(class) = weight;
+        }
+        self.config.demand.classes = classes;
+        Ok(())
+    }
+
+    // Whether the run ends by itself
+    pub fn has_end(&self) -> bool {
+        self.duration.is_some() || self.vehicles.is_some()
+    }
+
+    // Whether the run has reached its end
+    pub fn is_finished(&self, simulation: &Simulation) -> bool {
+        self.duration
+            .is_some_and(|duration| simulation.time() >= duration)
+            || self
+                .vehicles
+                .is_some_and(|vehicles| simulation.stats().total_cars >= vehicles)
+    }
+}
+
+fn number<T: std::str::FromStr>(option: &str, value: String) -> Result<T, String>
+where
+    T::Err: std::fmt::Display,
+{
+    value
+        .parse()
+        .map_err(|err| format!("invalid value {:?} for {}: {}", value, option, err))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(args: &str) -> Options {
+        Options::parse(args.split_whitespace().map(String::from)).unwrap()
+    }
+
+    // A demand file given last must not undo --rate and --classes
+    #[test]
+    fn rate_and_classes_override_the_demand_file_in_any_order() {
+        let demand = "--arrivals assets/demand/morning-peak.toml";
+        let overrides = "--rate L=5 --classes bus=1";
+        let before = parse(&format!("{} {}", overrides, demand));
+        let after = parse(&format!("{} {}", demand, overrides));
+        assert_eq!(before, after);
+        let left = before
+            .config
+            .demand
+            .approaches
+            .iter()
+            .find(|demand| demand.approach == Approach::Left)
+            .unwrap();
+        assert_eq!(left.rate, 5.);
+        assert_eq!(before.config.demand.classes.weight(VehicleClass::Car), 0.);
+    }
+
+    // Rates that would stop or stall the arrivals are refused
+    #[test]
+    fn rate_must_be_finite_and_not_negative() {
+        for rate in ["inf", "NaN", "-1", "D=inf"] {
+            assert!(
+                Options::parse(["--rate", rate].map(String::from)).is_err(),
+                "{}",
+                rate
+            );
+        }
+        assert!(Options::parse(["--rate", "0"].map(String::from)).is_ok());
+    }
+}
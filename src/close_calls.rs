@@ -0,0 +1,54 @@
+use crate::car::*;
+use crate::simulation::TIME_STEP;
+use crate::spatial::*;
+use crate::stats::*;
+use std::collections::HashSet;
+
+// Counts a close call every time two cars come closer to each other than
+// the safety distance, once per encounter: the pair has to move apart
+// again before it can count another one
+pub struct CloseCallDetector {
+    safety_distance: f32,
+    encounters: HashSet<(u64, u64)>,
+}
+
+impl CloseCallDetector {
+    pub fn new(safety_distance: f32) -> Self {
+        CloseCallDetector {
+            safety_distance,
+            encounters: HashSet::new(),
+        }
+    }
+
+    // The grid was built before the cars moved this step, so it is
+    // searched a step's worth of travel beyond the safety distance
+    pub fn update(&mut self, cars: &mut [Car], grid: &SpatialGrid, statistics: &mut Stats) {
+        let mut encounters = HashSet::new();
+        let mut new_encounters = Vec::new();
+        let margin = self.safety_distance + MAX_SPEED * TIME_STEP;
+        for (index, car) in cars.iter().enumerate() {
+            let body = car.body();
+            for other_index in grid.query(&grown(&car.car_rect, margin)) {
+                let other_car = &cars[other_index];
+                if other_index > index && body.distance_to(&other_car.body()) < self.safety_distance
+                {
+                    let encounter = pair(car.id, other_car.id);
+                    if !self.encounters.contains(&encounter) {
+                        new_encounters.push((index, other_index));
+                    }
+                    encounters.insert(encounter);
+                }
+            }
+        }
+        statistics.close_calls += new_encounters.len() as u32;
+        for (index, other_index) in new_encounters {
+            cars[index].close_calls += 1;
+            cars[other_index].close_calls += 1;
+        }
+        self.encounters = encounters;
+    }
+}
+
+fn pair(car: u64, other_car: u64) -> (u64, u64) {
+    (car.min(other_car), car.max(other_car))
+}
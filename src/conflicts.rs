@@ -0,0 +1,197 @@
+use crate::car::*;
+use crate::geometry::*;
+use crate::layout::*;
+use crate::route::*;
+use crate::simulation::TIME_STEP;
+use crate::spatial::*;
+use crate::vehicles::*;
+use macroquad::prelude::*;
+use std::collections::HashMap;
+use std::fmt;
+
+const MAX_CROSSING_STEPS: usize = 1200;
+// Distance below which the paths of two routes count as meeting, fixed so
+// that the conflicts do not change with the close call threshold
+const CLEARANCE: f32 = STANDSTILL_GAP * 0.75;
+
+// How the paths of two routes meet inside the core intersection
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Conflict {
+    None,
+    // Same route, cars only follow each other
+    Follow,
+    // Paths that start on the same approach and split up
+    Diverge,
+    // Paths that end on the same exit and join
+    Merge,
+    Cross,
+}
+
+impl Conflict {
+    pub fn symbol(self) -> char {
+        match self {
+            Conflict::None => '.',
+            Conflict::Follow => '=',
+            Conflict::Diverge => 'D',
+            Conflict::Merge => 'M',
+            Conflict::Cross => 'X',
+        }
+    }
+}
+
+// Route against route table of conflicts, worked out from the paths the
+// lanes of a layout take through the core intersection. Paths meet when
+// vehicles of any class on them can come closer than CLEARANCE.
+#[derive(Debug, Clone)]
+pub struct ConflictMatrix {
+    routes: Vec<Route>,
+    table: HashMap<(Route, Route), Conflict>,
+}
+
+impl ConflictMatrix {
+    pub fn from_layout(layout: &Layout) -> Self {
+        let core = layout.core_intersection();
+        let paths: Vec<(Route, Vec<OrientedRect>)> = layout
+            .lanes
+            .iter()
+            .map(|lane| {
+                // Every class sweeps its own footprint through the turns
+                let footprints = VehicleClass::ALL
+                    .into_iter()
+                    .flat_map(|class| {
+                        Car::new(lane, 0, 0., MAX_SPEED, class)
+                            .predict_crossing(TIME_STEP, &core, MAX_CROSSING_STEPS)
+                            .unwrap_or_default()
+                    })
+                    .collect();
+                (lane.route, footprints)
+            })
+            .collect();
+
+        let mut table = HashMap::new();
+        for (route, path) in &paths {
+            for (other_route, other_path) in &paths {
+                let conflict = if route == other_route {
+                    Conflict::Follow
+                } else if !paths_meet(path, other_path) {
+                    Conflict::None
+                } else if route.approach == other_route.approach {
+                    Conflict::Diverge
+                } else if route.exit() == other_route.exit() {
+                    Conflict::Merge
+                } else {
+                    Conflict::Cross
+                };
+                table.insert((*route, *other_route), conflict);
+            }
+        }
+
+        ConflictMatrix {
+            routes: paths.iter().map(|(route, _)| *route).collect(),
+            table,
+        }
+    }
+
+    pub fn conflict(&self, route: Route, other_route: Route) -> Conflict {
+        self.table
+            .get(&(route, other_route))
+            .copied()
+            .unwrap_or(Conflict::None)
+    }
+
+    // Whether cars on these routes may never be inside the core together
+    // without coordination. Cars on the same route just keep their distance
+    // with their radar.
+    pub fn conflicts(&self, route: Route, other_route: Route) -> bool {
+        !matches!(
+            self.conflict(route, other_route),
+            Conflict::None | Conflict::Follow
+        )
+    }
+
+    pub fn draw(&self, x: f32, y: f32) {
+        let cell = 26.;
+        draw_text("Conflicts", x, y - cell, 28., BLACK);
+        for (index, route) in self.routes.iter().enumerate() {
+            let offset = (index + 1) as f32 * cell;
+            draw_text(&route.code(), x + offset, y, 20., BLACK);
+            draw_text(&route.code(), x, y + offset, 20., BLACK);
+        }
+        for (row, route) in self.routes.iter().enumerate() {
+            for (column, other_route) in self.routes.iter().enumerate() {
+                let conflict = self.conflict(*route, *other_route);
+                let color = match conflict {
+                    Conflict::None => GRAY,
+                    Conflict::Follow => DARKGRAY,
+                    Conflict::Diverge | Conflict::Merge => ORANGE,
+                    Conflict::Cross => RED,
+                };
+                draw_text(
+                    &conflict.symbol().to_string(),
+                    x + (column + 1) as f32 * cell + 6.,
+                    y + (row + 1) as f32 * cell,
+                    20.,
+                    color,
+                );
+            }
+        }
+    }
+}
+
+impl fmt::Display for ConflictMatrix {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "  ")?;
+        for route in &self.routes {
+            write!(f, " {}", route)?;
+        }
+        writeln!(f)?;
+        for route in &self.routes {
+            write!(f, "{}", route)?;
+            for other_route in &self.routes {
+                write!(f, "  {}", self.conflict(*route, *other_route).symbol())?;
+            }
+            writeln!(f)?;
+        }
+        Ok(())
+    }
+}
+
+fn paths_meet(path: &[OrientedRect], other_path: &[OrientedRect]) -> bool {
+    // Cheap bounding box test first, most footprints are far apart
+    let other_areas: Vec<Rect> = other_path
+        .iter()
+        .map(|other_footprint| other_footprint.bounding_rect())
+        .collect();
+    path.iter().any(|footprint| {
+        let area = grown(&footprint.bounding_rect(), CLEARANCE);
+        other_path
+            .iter()
+            .zip(&other_areas)
+            .any(|(other_footprint, other_area)| {
+                area.overlaps(other_area) && footprint.distance_to(other_footprint) < CLEARANCE
+            })
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // A car going straight on sees no conflict with the right turn next to
+    // it, but the rear of a bus or truck turning right swings close to it
+    #[test]
+    fn long_vehicles_turning_right_conflict_with_the_next_lane() {
+        let layout = Layout::load("assets/intersection.toml").unwrap();
+        let conflicts = ConflictMatrix::from_layout(&layout);
+        let right_turn = Route::new(Approach::Up, Turn::Right);
+        let straight_on = Route::new(Approach::Up, Turn::Straight);
+        assert_eq!(
+            conflicts.conflict(right_turn, straight_on),
+            Conflict::Diverge
+        );
+        assert_eq!(
+            conflicts.conflict(straight_on, right_turn),
+            Conflict::Diverge
+        );
+    }
+}
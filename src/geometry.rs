@@ -0,0 +1,112 @@
+use macroquad::prelude::*;
+
+// A rectangle turned by `angle` radians around its centre, the long side
+// lying along the angle
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct OrientedRect {
+    pub center: Vec2,
+    pub half_length: f32,
+    pub half_width: f32,
+    pub angle: f32,
+}
+
+impl OrientedRect {
+    pub fn new(center: Vec2, length: f32, width: f32, angle: f32) -> Self {
+        OrientedRect {
+            center,
+            half_length: length / 2.,
+            half_width: width / 2.,
+            angle,
+        }
+    }
+
+    pub fn axes(&self) -> [Vec2; 2] {
+        let along = Vec2::from_angle(self.angle);
+        [along, along.perp()]
+    }
+
+    pub fn corners(&self) -> [Vec2; 4] {
+        let [along, across] = self.axes();
+        let along = along * self.half_length;
+        let across = across * self.half_width;
+        [
+            self.center + along + across,
+            self.center + along - across,
+            self.center - along - across,
+            self.center - along + across,
+        ]
+    }
+
+    // Smallest axis aligned rectangle holding the whole rectangle
+    pub fn bounding_rect(&self) -> Rect {
+        let corners = self.corners();
+        let min = corners
+            .iter()
+            .fold(corners[0], |min, corner| min.min(*corner));
+        let max = corners
+            .iter()
+            .fold(corners[0], |max, corner| max.max(*corner));
+        Rect::new(min.x, min.y, max.x - min.x, max.y - min.y)
+    }
+
+    // The same rectangle grown by `margin` on every side
+    pub fn inflated(&self, margin: f32) -> Self {
+        OrientedRect {
+            half_length: self.half_length + margin,
+            half_width: self.half_width + margin,
+            ..*self
+        }
+    }
+
+    // Separating axis test, touching edges do not count as intersecting
+    pub fn intersects(&self, other: &OrientedRect) -> bool {
+        let corners = self.corners();
+        let other_corners = other.corners();
+        self.axes().iter().chain(other.axes().iter()).all(|axis| {
+            let (min, max) = project(&corners, *axis);
+            let (other_min, other_max) = project(&other_corners, *axis);
+            min < other_max && other_min < max
+        })
+    }
+
+    // Shortest distance between the outlines, 0 when they intersect
+    pub fn distance_to(&self, other: &OrientedRect) -> f32 {
+        if self.intersects(other) {
+            return 0.;
+        }
+        let corners = self.corners();
+        let other_corners = other.corners();
+        let closest = |points: &[Vec2; 4], outline: &[Vec2; 4]| {
+            points
+                .iter()
+                .flat_map(|point| {
+                    (0..4).map(move |index| {
+                        point_to_segment(*point, outline[index], outline[(index + 1) % 4])
+                    })
+                })
+                .fold(f32::MAX, f32::min)
+        };
+        closest(&corners, &other_corners).min(closest(&other_corners, &corners))
+    }
+
+    pub fn draw(&self, color: Color) {
+        let [a, b, c, d] = self.corners();
+        draw_triangle(a, b, c, color);
+        draw_triangle(a, c, d, color);
+    }
+}
+
+fn project(corners: &[Vec2; 4], axis: Vec2) -> (f32, f32) {
+    corners
+        .iter()
+        .map(|corner| corner.dot(axis))
+        .fold((f32::MAX, f32::MIN), |(min, max), value| {
+            (min.min(value), max.max(value))
+        })
+}
+
+fn point_to_segment(point: Vec2, start: Vec2, end: Vec2) -> f32 {
+    let segment = end - start;
+    let t = ((point - start).dot(segment) / segment.length_squared()).clamp(0., 1.);
+    point.distance(start + segment * t)
+}
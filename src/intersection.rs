@@ -0,0 +1,588 @@
+use crate::car::*;
+use crate::conflicts::*;
+use crate::layout::*;
+use crate::policies::*;
+use crate::route::*;
+use crate::signals::*;
+use crate::simulation::TIME_STEP;
+use crate::spatial::*;
+use macroquad::prelude::*;
+use std::collections::{HashMap, HashSet};
+use std::iter;
+
+// Edge length of the square tiles the core intersection is divided into
+pub const TILE_SIZE: f32 = 15.;
+// Extra room around a car when working out which tiles it covers
+pub const TILE_MARGIN: f32 = 3.;
+// Steps a tile stays reserved before and after the car is expected on it
+pub const TIME_MARGIN: u64 = 6;
+// How far ahead a crossing is planned before the request is turned down
+const MAX_CROSSING_STEPS: usize = 1200;
+// How far before the core an emergency vehicle takes the right of way
+pub const EMERGENCY_PRIORITY_DISTANCE: f32 = 300.;
+// How far before the core cars start asking for a reservation, so there is
+// room to slow down to an approach speed instead of stopping at the core
+pub const PLANNING_DISTANCE: f32 = 200.;
+// Steps after which a car denied a reservation asks again, even if no
+// reservation has been given up since
+pub const RETRY_STEPS: u64 = 6;
+// Speeds a car may be asked to approach the core at when there is no
+// reservation for its cruising speed, the fastest first
+pub const APPROACH_SPEEDS: [f32; 4] = [90., 60., 40., 25.];
+
+// The policies available to hand out the right to cross the core
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Controller {
+    // Tile reservations, see IntersectionManager
+    Reservation,
+    // Whole core occupancy in order of arrival, see FirstComeFirstServed
+    Fcfs,
+    // Compatible routes take turns, see BatchRelease
+    Batch,
+    // Busiest routes first, see LongestQueueFirst
+    LongestQueue,
+    // Any car whose route is clear goes, see RuleSet
+    Rules,
+    // Traffic lights on fixed times, see SignalController
+    FixedTime,
+    // Traffic lights actuated by detectors, see SignalController
+    Actuated,
+}
+
+impl Controller {
+    pub const ALL: [Controller; 7] = [
+        Controller::Reservation,
+        Controller::Fcfs,
+        Controller::Batch,
+        Controller::LongestQueue,
+        Controller::Rules,
+        Controller::FixedTime,
+        Controller::Actuated,
+    ];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Controller::Reservation => "reservation",
+            Controller::Fcfs => "fcfs",
+            Controller::Batch => "batch",
+            Controller::LongestQueue => "longest-queue",
+            Controller::Rules => "rules",
+            Controller::FixedTime => "fixed-time",
+            Controller::Actuated => "actuated",
+        }
+    }
+
+    pub fn is_signalised(self) -> bool {
+        matches!(self, Controller::FixedTime | Controller::Actuated)
+    }
+
+    pub fn build(
+        self,
+        layout: &Layout,
+        conflicts: &ConflictMatrix,
+        signals: &Option<SignalPlan>,
+    ) -> Box<dyn IntersectionController> {
+        let core = layout.core_intersection();
+        let signals = signals
+            .clone()
+            .unwrap_or_else(|| SignalPlan::per_approach(conflicts));
+        let conflicts = conflicts.clone();
+        match self {
+            Controller::Reservation => Box::new(IntersectionManager::new(core, conflicts)),
+            Controller::Fcfs => Box::new(FirstComeFirstServed::new(core, conflicts)),
+            Controller::Batch => Box::new(BatchRelease::new(core, conflicts)),
+            Controller::LongestQueue => Box::new(LongestQueueFirst::new(core, conflicts)),
+            Controller::Rules => Box::new(RuleSet::new(core, conflicts)),
+            Controller::FixedTime => {
+                Box::new(SignalController::new(layout, conflicts, signals, false))
+            }
+            Controller::Actuated => {
+                Box::new(SignalController::new(layout, conflicts, signals, true))
+            }
+        }
+    }
+}
+
+impl TryFrom<String> for Controller {
+    type Error = String;
+
+    fn try_from(name: String) -> Result<Self, Self::Error> {
+        Controller::ALL
+            .into_iter()
+            .find(|controller| controller.name() == name)
+            .ok_or_else(|| format!("unknown controller {:?}", name))
+    }
+}
+
+// Decides which of the cars reaching the core intersection may enter it.
+// Cars are approaching once their radar reaches into the core, and a
+// controller holds one back by setting its waiting_flag.
+pub trait IntersectionController {
+    // Called once per step before the radars are scanned and the cars
+    // move, so it sees their positions and radars of the previous step
+    fn update(&mut self, cars: &mut [Car], now: u64);
+
+    // Shows the state of the controller in debug mode
+    fn draw(&self, _now: u64) {}
+
+    // Draws whatever drivers are meant to see, such as traffic lights
+    fn draw_signals(&self) {}
+}
+
+pub fn is_inside(car: &Car, core: &Rect) -> bool {
+    car.car_rect.intersect(*core).is_some()
+}
+
+pub fn is_approaching(car: &Car, core: &Rect) -> bool {
+    !is_inside(car, core) && car.radar.intersect(*core).is_some()
+}
+
+fn has_crossed(car: &Car, core: &Rect) -> bool {
+    car.time_in_intersection > 0. && !is_inside(car, core)
+}
+
+// Gives emergency vehicles coming up to the core the right of way over
+// whatever the controller decided, called after it. The lanes have no room
+// to pull over, so the cars ahead of an emergency vehicle on its lane
+// clear the way by crossing ahead of it with the same priority. Cars on
+// conflicting routes yield at the edge of the core, while the ones with
+// priority wait for those already inside to leave. An emergency vehicle
+// on a route conflicting with an earlier one yields to it like any car.
+pub fn give_way_to_emergencies(cars: &mut [Car], core: &Rect, conflicts: &ConflictMatrix) {
+    cars.iter_mut().for_each(|car| car.yielding = false);
+
+    // Routes with the right of way, each with the id of the last car on it
+    // that has it
+    let mut priority: Vec<(Route, u64)> = Vec::new();
+    for car in cars
+        .iter()
+        .filter(|car| car.is_emergency && !has_crossed(car, core))
+    {
+        let is_near = is_inside(car, core)
+            || grown(&car.car_rect, EMERGENCY_PRIORITY_DISTANCE).overlaps(core);
+        let is_clear = priority
+            .iter()
+            .all(|&(route, _)| !conflicts.conflicts(car.route, route));
+        if !is_near || !is_clear {
+            continue;
+        }
+        match priority.iter_mut().find(|(route, _)| *route == car.route) {
+            Some(last) => last.1 = car.id,
+            None => priority.push((car.route, car.id)),
+        }
+    }
+    if priority.is_empty() {
+        return;
+    }
+
+    let has_priority = |car: &Car| {
+        priority
+            .iter()
+            .any(|&(route, last)| car.route == route && car.id <= last)
+            && !has_crossed(car, core)
+    };
+    let conflicting = |car: &Car, route: Route| conflicts.conflicts(car.route, route);
+    let busy_routes: Vec<Route> = priority
+        .iter()
+        .map(|&(route, _)| route)
+        .filter(|&route| {
+            cars.iter()
+                .any(|car| is_inside(car, core) && !has_priority(car) && conflicting(car, route))
+        })
+        .collect();
+    for car in cars.iter_mut() {
+        if !is_approaching(car, core) {
+            continue;
+        }
+        if has_priority(car) {
+            car.waiting_flag = false;
+            car.yielding = busy_routes.contains(&car.route);
+        } else if priority.iter().any(|&(route, _)| conflicting(car, route)) {
+            car.yielding = true;
+        }
+    }
+}
+
+// What a car is told once its request is granted
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct Plan {
+    // None to approach at its own cruising speed
+    speed: Option<f32>,
+    arrival_step: u64,
+}
+
+impl Plan {
+    fn hand_to(self, car: &mut Car) {
+        car.waiting_flag = false;
+        car.planned_speed = self.speed;
+        car.target_arrival = Some(self.arrival_step as f32 * TIME_STEP);
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct Reservation {
+    car: u64,
+    route: Route,
+    from_step: u64,
+    to_step: u64,
+}
+
+// Grants cars the right to cross the core intersection by reserving the
+// tiles they will cover for the steps they are expected to cover them, so
+// cars on paths that do not meet can cross at the same time. Tiles are
+// only contended by routes the conflict matrix says meet.
+pub struct IntersectionManager {
+    core: Rect,
+    conflicts: ConflictMatrix,
+    columns: usize,
+    rows: usize,
+    tiles: Vec<Vec<Reservation>>,
+    granted: HashSet<u64>,
+    // Times reservations were given up, and for every car that was denied
+    // a reservation or a faster one the count and step of its last try
+    releases: u64,
+    last_tries: HashMap<u64, (u64, u64)>,
+}
+
+impl IntersectionManager {
+    pub fn new(core: Rect, conflicts: ConflictMatrix) -> Self {
+        let columns = (core.w / TILE_SIZE).ceil() as usize;
+        let rows = (core.h / TILE_SIZE).ceil() as usize;
+        IntersectionManager {
+            core,
+            conflicts,
+            columns,
+            rows,
+            tiles: vec![Vec::new(); columns * rows],
+            granted: HashSet::new(),
+            releases: 0,
+            last_tries: HashMap::new(),
+        }
+    }
+
+    pub fn has_reservation(&self, car: &Car) -> bool {
+        self.granted.contains(&car.id)
+    }
+
+    // Looks for a reservation for the car at its cruising speed, then at
+    // ever slower approach speeds down to just above `slowest`, so it
+    // reaches the core just in time rather than having to stop at it
+    fn request(
+        &mut self,
+        car: &Car,
+        leader_speed: Option<f32>,
+        slowest: f32,
+        now: u64,
+    ) -> Option<Plan> {
+        let mut planned_car = car.clone();
+        if let Some(leader_speed) = leader_speed {
+            planned_car.randomized_initial_speed = car.randomized_initial_speed.min(leader_speed);
+        }
+        let cruising_speed = planned_car.randomized_initial_speed;
+        let speeds = iter::once(cruising_speed).chain(
+            APPROACH_SPEEDS
+                .into_iter()
+                .filter(|&speed| speed < cruising_speed),
+        );
+        for speed in speeds.take_while(|&speed| speed > slowest) {
+            planned_car.planned_speed = (speed < car.randomized_initial_speed).then_some(speed);
+            if let Some(arrival_step) = self.reserve(&planned_car, now) {
+                return Some(Plan {
+                    speed: planned_car.planned_speed,
+                    arrival_step,
+                });
+            }
+        }
+        None
+    }
+
+    // Reserves the tiles the car covers while crossing as it is planned to
+    // drive, if they are free, and returns the step it reaches the core
+    fn reserve(&mut self, car: &Car, now: u64) -> Option<u64> {
+        // A footprint on a tile held by a conflicting car at that very step
+        // rules the crossing out without predicting the rest of it
+        let conflicts_at = |tile: usize, from_step: u64, to_step: u64| {
+            self.tiles[tile].iter().any(|reservation| {
+                reservation.car != car.id
+                    && self.conflicts.conflicts(car.route, reservation.route)
+                    && reservation.to_step + TIME_MARGIN >= from_step
+                    && to_step + TIME_MARGIN >= reservation.from_step
+            })
+        };
+        let footprints = car.predict_crossing_until(
+            TIME_STEP,
+            &self.core,
+            MAX_CROSSING_STEPS,
+            |index, footprint| {
+                let step = now + index as u64 + 1;
+                self.tiles_under(&footprint.bounding_rect())
+                    .into_iter()
+                    .any(|tile| conflicts_at(tile, step, step))
+            },
+        )?;
+
+        let mut wanted: HashMap<usize, (u64, u64)> = HashMap::new();
+        for (index, footprint) in footprints.iter().enumerate() {
+            let step = now + index as u64 + 1;
+            for tile in self.tiles_under(&footprint.bounding_rect()) {
+                let interval = wanted.entry(tile).or_insert((step, step));
+                interval.0 = interval.0.min(step);
+                interval.1 = interval.1.max(step);
+            }
+        }
+
+        let is_free = wanted
+            .iter()
+            .all(|(&tile, &(from_step, to_step))| !conflicts_at(tile, from_step, to_step));
+        if !is_free {
+            return None;
+        }
+        for (tile, (from_step, to_step)) in wanted {
+            self.tiles[tile].push(Reservation {
+                car: car.id,
+                route: car.route,
+                from_step,
+                to_step,
+            });
+        }
+        self.granted.insert(car.id);
+        let arrival_index = footprints
+            .iter()
+            .position(|footprint| footprint.bounding_rect().overlaps(&self.core))
+            .unwrap_or(0);
+        Some(now + arrival_index as u64 + 1)
+    }
+
+    // Swaps the reservation of a car slowed down on its way to the core for
+    // a faster one, if one has come free since it was planned. The old
+    // tiles are kept until the new ones are granted.
+    fn speed_up(&mut self, car: &Car, leader_speed: Option<f32>, now: u64) -> Option<Plan> {
+        let slowest = car.planned_speed?;
+        if !self.may_try(car, now) {
+            return None;
+        }
+        let held: Vec<(usize, Reservation)> = self
+            .tiles
+            .iter()
+            .enumerate()
+            .flat_map(|(index, tile)| {
+                tile.iter()
+                    .filter(|reservation| reservation.car == car.id)
+                    .map(move |&reservation| (index, reservation))
+            })
+            .collect();
+        let Some(plan) = self.request(car, leader_speed, slowest, now) else {
+            self.last_tries.insert(car.id, (self.releases, now));
+            return None;
+        };
+        for (index, reservation) in held {
+            let tile = &mut self.tiles[index];
+            if let Some(position) = tile.iter().position(|other| *other == reservation) {
+                tile.remove(position);
+            }
+        }
+        self.releases += 1;
+        Some(plan)
+    }
+
+    fn release(&mut self, car: u64) {
+        self.tiles
+            .iter_mut()
+            .for_each(|tile| tile.retain(|reservation| reservation.car != car));
+        self.granted.remove(&car);
+        self.last_tries.remove(&car);
+        self.releases += 1;
+    }
+
+    // A car denied before only asks again once a reservation has been
+    // given up since, or RETRY_STEPS later as the tiles it wants move on
+    fn may_try(&self, car: &Car, now: u64) -> bool {
+        self.last_tries
+            .get(&car.id)
+            .is_none_or(|&(releases, step)| releases != self.releases || now >= step + RETRY_STEPS)
+    }
+
+    // Indices of the tiles a rectangle touches, with TILE_MARGIN around it
+    fn tiles_under(&self, rect: &Rect) -> Vec<usize> {
+        let column_of = |x: f32| ((x - self.core.x) / TILE_SIZE).floor() as i32;
+        let row_of = |y: f32| ((y - self.core.y) / TILE_SIZE).floor() as i32;
+        let first_column = column_of(rect.x - TILE_MARGIN).max(0);
+        let last_column = column_of(rect.right() + TILE_MARGIN).min(self.columns as i32 - 1);
+        let first_row = row_of(rect.y - TILE_MARGIN).max(0);
+        let last_row = row_of(rect.bottom() + TILE_MARGIN).min(self.rows as i32 - 1);
+
+        let mut tiles = Vec::new();
+        for row in first_row..=last_row {
+            for column in first_column..=last_column {
+                tiles.push(row as usize * self.columns + column as usize);
+            }
+        }
+        tiles
+    }
+}
+
+impl IntersectionController for IntersectionManager {
+    // Lets through the cars holding a reservation, plans a crossing for
+    // the cars coming up to the core and frees the tiles of cars that have
+    // crossed. A car that has fallen behind its plan before reaching the
+    // core gives its reservation up and is planned again.
+    fn update(&mut self, cars: &mut [Car], now: u64) {
+        self.tiles
+            .iter_mut()
+            .for_each(|tile| tile.retain(|reservation| reservation.to_step >= now));
+
+        // Cars following a granted car on their route are planned at no more
+        // than its cruising speed, as their radar will hold them behind it
+        let mut leader_speeds: HashMap<Route, f32> = HashMap::new();
+        for car in cars.iter().filter(|car| self.has_reservation(car)) {
+            let speed = leader_speeds.entry(car.route).or_insert(f32::MAX);
+            *speed = speed.min(car.randomized_initial_speed);
+        }
+
+        // Cars are in spawn order, which on every route is the order they
+        // drive in, and only the first car on a route without a reservation
+        // asks for one
+        let mut held_routes: HashSet<Route> = HashSet::new();
+        let late_step = now.saturating_sub(TIME_MARGIN);
+        for car in cars.iter_mut() {
+            // Emergency vehicles take the right of way rather than ask for it
+            if car.is_emergency {
+                continue;
+            }
+            let has_crossed = car.time_in_intersection > 0.;
+            if is_inside(car, &self.core) {
+                car.planned_speed = None;
+                car.target_arrival = None;
+                continue;
+            }
+            if has_crossed {
+                if self.has_reservation(car) {
+                    self.release(car.id);
+                }
+                continue;
+            }
+            if self.has_reservation(car) {
+                let is_late = car
+                    .target_arrival
+                    .is_some_and(|arrival| ((arrival / TIME_STEP).round() as u64) < late_step);
+                if !is_late {
+                    let leader_speed = leader_speeds.get(&car.route).copied();
+                    if let Some(plan) = self.speed_up(car, leader_speed, now) {
+                        plan.hand_to(car);
+                    }
+                    continue;
+                }
+                self.release(car.id);
+            }
+            if held_routes.contains(&car.route) {
+                continue;
+            }
+            let is_approaching = is_approaching(car, &self.core);
+            if !is_approaching && !grown(&car.car_rect, PLANNING_DISTANCE).overlaps(&self.core) {
+                continue;
+            }
+
+            let leader_speed = leader_speeds.get(&car.route).copied();
+            let may_try = self.may_try(car, now);
+            let plan = if may_try {
+                self.request(car, leader_speed, 0., now)
+            } else {
+                None
+            };
+            match plan {
+                Some(plan) => {
+                    self.last_tries.remove(&car.id);
+                    plan.hand_to(car);
+                    let speed = leader_speeds.entry(car.route).or_insert(f32::MAX);
+                    *speed = speed.min(car.randomized_initial_speed);
+                }
+                None => {
+                    if may_try {
+                        self.last_tries.insert(car.id, (self.releases, now));
+                    }
+                    car.planned_speed = None;
+                    car.target_arrival = None;
+                    car.waiting_flag = is_approaching;
+                    held_routes.insert(car.route);
+                }
+            }
+        }
+    }
+
+    // Highlights the tiles reserved for the current step
+    fn draw(&self, now: u64) {
+        for (index, tile) in self.tiles.iter().enumerate() {
+            let x = self.core.x + (index % self.columns) as f32 * TILE_SIZE;
+            let y = self.core.y + (index / self.columns) as f32 * TILE_SIZE;
+            draw_rectangle_lines(x, y, TILE_SIZE, TILE_SIZE, 1., Color::new(0., 0., 0., 0.1));
+            if tile
+                .iter()
+                .any(|reservation| reservation.from_step <= now && now <= reservation.to_step)
+            {
+                draw_rectangle(x, y, TILE_SIZE, TILE_SIZE, Color::new(0., 0., 1., 0.2));
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::vehicles::*;
+
+    // A car on `route` just short of the core, its radar reaching into it
+    fn approaching_car(layout: &Layout, route: Route, id: u64) -> Car {
+        let core = layout.core_intersection();
+        let lane = layout.lane(route).unwrap();
+        let mut car = Car::new(lane, id, 0., MAX_SPEED, VehicleClass::Car);
+        while !grown(&car.car_rect, 10.).overlaps(&core) {
+            car.advance(1.);
+        }
+        let cars = [car.clone()];
+        let mut grid = SpatialGrid::new();
+        grid.rebuild(&cars);
+        car.radar = car.scan_radar(0, &cars, &grid);
+        car
+    }
+
+    // While a car holds every tile of the core, a car on a crossing route
+    // is held at the core and one on a route clear of it is let through.
+    // Once the tiles are given up the held car gets its reservation.
+    #[test]
+    fn crossing_is_granted_only_on_tiles_no_conflicting_car_holds() {
+        let layout = Layout::load("assets/intersection.toml").unwrap();
+        let conflicts = ConflictMatrix::from_layout(&layout);
+        let holder_route = Route::new(Approach::Left, Turn::Straight);
+        let crossing = Route::new(Approach::Down, Turn::Straight);
+        let opposite = Route::new(Approach::Right, Turn::Straight);
+        assert!(conflicts.conflicts(crossing, holder_route));
+        assert!(!conflicts.conflicts(opposite, holder_route));
+
+        let mut manager = IntersectionManager::new(layout.core_intersection(), conflicts);
+        let holder = 99;
+        for tile in &mut manager.tiles {
+            tile.push(Reservation {
+                car: holder,
+                route: holder_route,
+                from_step: 0,
+                to_step: 10_000,
+            });
+        }
+        manager.granted.insert(holder);
+
+        let mut cars = vec![
+            approaching_car(&layout, crossing, 0),
+            approaching_car(&layout, opposite, 1),
+        ];
+        manager.update(&mut cars, 0);
+        assert!(!manager.has_reservation(&cars[0]));
+        assert!(cars[0].waiting_flag);
+        assert!(manager.has_reservation(&cars[1]));
+        assert!(!cars[1].waiting_flag);
+
+        manager.release(holder);
+        manager.update(&mut cars, 1);
+        assert!(manager.has_reservation(&cars[0]));
+        assert!(!cars[0].waiting_flag);
+    }
+}
@@ -0,0 +1,178 @@
+use crate::car::CAR_SIZE;
+use crate::path::*;
+use crate::route::*;
+use macroquad::prelude::*;
+use serde::Deserialize;
+use std::fs;
+use std::sync::Arc;
+
+// Turn radius for turning lanes that do not set one
+pub const DEFAULT_TURN_RADIUS: f32 = 40.;
+// How far paths run on past their exit point, well beyond the despawn lines
+const EXIT_RUN: f32 = 2000.;
+
+// Intersection geometry, loaded from a layout file such as
+// assets/intersection.toml
+#[derive(Debug, Clone, Deserialize)]
+pub struct Layout {
+    pub core_intersection: Area,
+    pub despawn: DespawnLines,
+    pub lanes: Vec<Lane>,
+}
+
+#[derive(Debug, Clone, Copy, Deserialize)]
+pub struct Area {
+    pub x: f32,
+    pub y: f32,
+    pub w: f32,
+    pub h: f32,
+}
+
+// Coordinates past which a car heading that way has left the road
+#[derive(Debug, Clone, Copy, Deserialize)]
+pub struct DespawnLines {
+    pub west: f32,
+    pub north: f32,
+    pub south: f32,
+    pub east: f32,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct Lane {
+    pub route: Route,
+    #[serde(with = "point")]
+    pub entry: Vec2,
+    pub turn: Option<f32>,
+    pub radius: Option<f32>,
+    // Centre line of the lane, built from the fields above when loading
+    #[serde(skip)]
+    pub path: Arc<Path>,
+}
+
+impl Layout {
+    pub fn load(path: &str) -> Result<Self, String> {
+        let contents =
+            fs::read_to_string(path).map_err(|err| format!("cannot read {}: {}", path, err))?;
+        Layout::parse(&contents).map_err(|err| format!("invalid layout {}: {}", path, err))
+    }
+
+    pub fn parse(contents: &str) -> Result<Self, String> {
+        let mut layout: Layout = toml::from_str(contents).map_err(|err| err.to_string())?;
+        for lane in &layout.lanes {
+            if layout
+                .lanes
+                .iter()
+                .filter(|other| other.route == lane.route)
+                .count()
+                > 1
+            {
+                return Err(format!("lane {} is defined more than once", lane.route));
+            }
+            if lane.turn.is_none() != (lane.route.turn == Turn::Straight) {
+                return Err(format!(
+                    "lane {} must have a turn line only if it turns",
+                    lane.route
+                ));
+            }
+        }
+        for lane in &mut layout.lanes {
+            lane.path = Arc::new(lane.centre_line());
+        }
+        Ok(layout)
+    }
+
+    pub fn core_intersection(&self) -> Rect {
+        let area = self.core_intersection;
+        Rect::new(area.x, area.y, area.w, area.h)
+    }
+
+    pub fn lane(&self, route: Route) -> Option<&Lane> {
+        self.lanes.iter().find(|lane| lane.route == route)
+    }
+
+    // Marks where every lane starts
+    pub fn draw_lanes(&self) {
+        for lane in &self.lanes {
+            draw_circle(lane.entry.x, lane.entry.y, 4., GREEN);
+            draw_text(
+                &lane.route.code(),
+                lane.entry.x + 6.,
+                lane.entry.y - 4.,
+                18.,
+                BLACK,
+            );
+        }
+    }
+}
+
+impl Lane {
+    // Shows how many cars are waiting to enter the lane, just behind its
+    // entry point
+    pub fn draw_queue(&self, queue_length: usize) {
+        if queue_length == 0 {
+            return;
+        }
+        let behind = self.path.point_at(0.) - self.route.entry_heading().vector() * CAR_SIZE.x;
+        let text = format!("+{}", queue_length);
+        let size = measure_text(&text, None, 28, 1.);
+        draw_text(
+            &text,
+            behind.x - size.width / 2.,
+            behind.y + size.height / 2.,
+            28.,
+            RED,
+        );
+    }
+
+    // Traces the way a car takes along the lane, labelled with its route
+    pub fn draw_route(&self, thickness: f32, color: Color) {
+        self.path.draw(thickness, color);
+        draw_text(
+            &self.route.code(),
+            self.entry.x + 6.,
+            self.entry.y - 4.,
+            24.,
+            color,
+        );
+    }
+
+    // Lane points are given for a car of CAR_SIZE, the centre line runs
+    // half a car length and width in from them
+    fn centre_line(&self) -> Path {
+        let heading = self.route.entry_heading();
+        let start = match heading {
+            Direction::West | Direction::East => self.entry + CAR_SIZE / 2.,
+            Direction::North | Direction::South => self.entry + vec2(CAR_SIZE.y, CAR_SIZE.x) / 2.,
+        };
+        let Some(turn_line) = self.turn else {
+            return Path::straight(start, heading.vector(), EXIT_RUN);
+        };
+
+        // The exit lane lies just behind the turn line, as seen from the
+        // entry heading
+        let along = heading.vector();
+        let exit_centre = turn_line - (along.x + along.y) * CAR_SIZE.y / 2.;
+        let corner = match heading {
+            Direction::West | Direction::East => vec2(exit_centre, start.y),
+            Direction::North | Direction::South => vec2(start.x, exit_centre),
+        };
+        Path::turning(
+            start,
+            corner,
+            self.radius.unwrap_or(DEFAULT_TURN_RADIUS),
+            self.route.exit_heading().vector(),
+            EXIT_RUN,
+        )
+    }
+}
+
+// Points are written as [x, y] arrays in layout files
+mod point {
+    use macroquad::prelude::*;
+    use serde::{Deserialize, Deserializer};
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec2, D::Error> {
+        let [x, y] = <[f32; 2]>::deserialize(deserializer)?;
+        Ok(vec2(x, y))
+    }
+}
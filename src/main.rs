@@ -1,10 +1,41 @@
 use macroquad::input::KeyCode::{Down, Left, Right, Up};
-use macroquad::{prelude::*, rand::gen_range};
+use macroquad::prelude::*;
 use std::default::Default;
-mod stats;
-use stats::*;
+mod arrivals;
 mod car;
+mod cli;
+mod close_calls;
+mod conflicts;
+mod geometry;
+mod intersection;
+mod layout;
+mod path;
+mod policies;
+mod recording;
+mod route;
+mod scenario;
+mod signals;
+mod simulation;
+mod spatial;
+mod stats;
+mod trips;
+mod vehicles;
 use car::*;
+use cli::*;
+use layout::*;
+use recording::*;
+use route::*;
+use scenario::*;
+use signals::*;
+use simulation::*;
+use stats::*;
+use trips::*;
+
+// Upper bound of real time simulated per frame, so a stalled frame
+// does not trigger a long burst of catch-up steps
+const MAX_FRAME_TIME: f32 = 0.25;
+// Seconds the route of a car spawned with a chosen turn stays highlighted
+const CHOSEN_ROUTE_TIME: f32 = 1.5;
 
 fn conf() -> Conf {
     Conf {
@@ -17,28 +48,220 @@ fn conf() -> Conf {
     }
 }
 
-#[macroquad::main(conf)]
-async fn main() {
-    // Initial game variables
-    let mut statistics: Stats = Stats {
-        total_cars: 0,
-        best_time: 999999999.,
-        worst_time: 0.,
-        best_velocity: 0.,
-        worst_velocity: 999999999.,
-        collisions: 0,
-        close_calls: 0,
+fn draw_simulation(
+    simulation: &Simulation,
+    cross_road: &Texture2D,
+    sprites: &Sprites,
+    is_debug_mode: bool,
+    marked_routes: &[(Route, f32, Color)],
+) {
+    // Draw the cross roads aka the background
+    draw_texture(cross_road, 0., 0., WHITE);
+    if is_debug_mode {
+        let core_intersection = simulation.layout().core_intersection();
+        draw_rectangle(
+            core_intersection.x,
+            core_intersection.y,
+            core_intersection.w,
+            core_intersection.h,
+            Color::new(0.5, 0.5, 0., 0.1),
+        );
+        simulation.intersection().draw(simulation.steps());
+        simulation.conflicts().draw(30., 880.);
+        simulation.layout().draw_lanes();
+    }
+
+    for lane in &simulation.layout().lanes {
+        lane.draw_queue(simulation.queue_length(lane.route));
+    }
+    simulation.intersection().draw_signals();
+    for &(route, thickness, color) in marked_routes {
+        if let Some(lane) = simulation.layout().lane(route) {
+            lane.draw_route(thickness, color);
+        }
+    }
+
+    //Draw the car_rect
+    simulation
+        .cars()
+        .iter()
+        .for_each(|car| car.draw_all_components(sprites, is_debug_mode));
+}
+
+// Steps the simulation until the options say the run is over and
+// reports the stats
+fn run_headless(mut simulation: Simulation, options: &Options, mut recorder: Option<Recorder>) {
+    // Every output is tried even if one fails, and the run goes on without
+    // a recording that cannot be written
+    let mut is_failed = false;
+    while !options.is_finished(&simulation) {
+        simulation.step();
+        if let Some(Err(err)) = recorder
+            .as_mut()
+            .map(|recorder| recorder.record(&simulation))
+        {
+            eprintln!("{}", err);
+            recorder = None;
+            is_failed = true;
+        }
+    }
+    let report = Report {
+        seed: simulation.seed(),
+        controller: simulation.controller().name(),
+        scenario: simulation.scenario_name(),
+        stats: simulation.stats(),
+    };
+    // A report that cannot be written goes to stdout so the run is not lost
+    if let Some(path) = &options.output {
+        let contents = if path.ends_with(".json") {
+            report.to_json()
+        } else {
+            report.to_string()
+        };
+        if let Err(err) = std::fs::write(path, contents) {
+            eprintln!("cannot write {}: {}", path, err);
+            print!("{}", report);
+            is_failed = true;
+        }
+    } else {
+        print!("{}", report);
+    }
+    if let Some(path) = &options.trips {
+        if let Err(err) = export_trips(simulation.trips(), path) {
+            eprintln!("{}", err);
+            is_failed = true;
+        }
+    }
+    if let Some(Err(err)) = recorder.map(Recorder::finish) {
+        eprintln!("{}", err);
+        is_failed = true;
+    }
+    if is_failed {
+        std::process::exit(1);
+    }
+}
+
+// The side an arrow key spawns a car from, the one the car drives away from
+fn pressed_approach() -> Option<Approach> {
+    if is_key_pressed(Left) {
+        Some(Approach::Right)
+    } else if is_key_pressed(Up) {
+        Some(Approach::Down)
+    } else if is_key_pressed(Down) {
+        Some(Approach::Up)
+    } else if is_key_pressed(Right) {
+        Some(Approach::Left)
+    } else {
+        None
+    }
+}
+
+// The turn chosen by holding 1, 2 or 3 for left, straight or right
+fn held_turn() -> Option<Turn> {
+    if is_key_down(KeyCode::Key1) {
+        Some(Turn::Left)
+    } else if is_key_down(KeyCode::Key2) {
+        Some(Turn::Straight)
+    } else if is_key_down(KeyCode::Key3) {
+        Some(Turn::Right)
+    } else {
+        None
+    }
+}
+
+fn main() {
+    let mut options = match Options::parse(std::env::args().skip(1)) {
+        Ok(options) => options,
+        Err(err) => {
+            eprintln!("{}\n\n{}", err, USAGE);
+            std::process::exit(2);
+        }
     };
+    if options.help {
+        println!("{}", USAGE);
+        return;
+    }
+    if let Some(path) = &options.replay {
+        let recording = Recording::load(path).unwrap_or_else(|err| {
+            eprintln!("{}", err);
+            std::process::exit(1);
+        });
+        macroquad::Window::from_config(conf(), run_replay(recording));
+        return;
+    }
 
+    let seed = options
+        .seed
+        .unwrap_or_else(|| (miniquad::date::now() * 1000.) as u64);
+    let layout = Layout::load(&options.layout).unwrap_or_else(|err| {
+        eprintln!("{}", err);
+        std::process::exit(2);
+    });
+    let mut simulation = Simulation::new(
+        layout,
+        SimulationConfig {
+            seed,
+            ..options.config.clone()
+        },
+    );
+    if options.config.controller.is_signalised() {
+        let signals = options
+            .config
+            .signals
+            .clone()
+            .unwrap_or_else(|| SignalPlan::per_approach(simulation.conflicts()));
+        if let Err(err) = signals.check(simulation.layout(), simulation.conflicts()) {
+            eprintln!("{}", err);
+            std::process::exit(2);
+        }
+    }
+    simulation.set_random_spawning(options.random);
+    if let Some(path) = &options.scenario {
+        let scenario = Scenario::load(path).unwrap_or_else(|err| {
+            eprintln!("{}", err);
+            std::process::exit(2);
+        });
+        simulation.set_scenario(&scenario);
+        options.duration = options.duration.or(scenario.duration);
+    }
+
+    let recorder = options.record.as_ref().map(|path| {
+        Recorder::create(path, &simulation).unwrap_or_else(|err| {
+            eprintln!("{}", err);
+            std::process::exit(1);
+        })
+    });
+
+    if options.headless {
+        if !options.has_end() {
+            eprintln!("--headless needs --duration or --vehicles, or a scenario with a duration");
+            std::process::exit(2);
+        }
+        run_headless(simulation, &options, recorder);
+    } else {
+        println!("Seed: {}", seed);
+        macroquad::Window::from_config(conf(), run_window(simulation, options, recorder));
+    }
+}
+
+// Runs the simulation in the window, showing the statistics screen once
+// the run has reached its end or Esc is pressed
+async fn run_window(mut simulation: Simulation, options: Options, mut recorder: Option<Recorder>) {
+    // Initial game variables
     let mut is_escaped: bool = false;
     let mut is_exit: bool = false;
     let mut is_paused = false;
-    let mut is_random = false;
     let mut is_debug_mode = false;
     let cross_road: Texture2D = load_texture("assets/cross-road.png").await.unwrap();
-    let car_texture: Texture2D = load_texture("assets/car.png").await.unwrap();
-    let mut cars: Vec<Car> = Vec::new();
-    let core_intersection = Rect::new(503., 520., 180., 180.);
+    let sprites = Sprites::load().await;
+    // Real time not yet consumed by fixed simulation steps
+    let mut time_accumulator: f32 = 0.;
+    // Route of the last car spawned with a chosen turn and the seconds
+    // left to highlight it
+    let mut chosen_route: Option<(Route, f32)> = None;
+    // Summary of the travel times for the statistics screen, taken once
+    // the run has ended
+    let mut final_travel_times: Option<Summary> = None;
 
     // GAME LOOP
 
@@ -51,34 +274,67 @@ async fn main() {
                 is_exit = true;
             }
         }
+        if !is_escaped && options.is_finished(&simulation) {
+            is_escaped = true;
+            is_exit = true;
+        }
         if is_key_pressed(KeyCode::P) {
             is_paused = !is_paused;
         }
+        if is_key_pressed(KeyCode::T) {
+            for path in ["trips.csv", "trips.json"] {
+                match export_trips(simulation.trips(), path) {
+                    Ok(()) => println!("Trips written to {}", path),
+                    Err(err) => eprintln!("{}", err),
+                }
+            }
+        }
         if is_key_pressed(KeyCode::D) {
             is_debug_mode = !is_debug_mode;
+            if is_debug_mode {
+                println!("{}", simulation.conflicts());
+            }
+        }
+
+        // While a turn is held every route it can make is traced, the one
+        // a car was spawned on last stands out for a moment
+        let turn = held_turn();
+        chosen_route = chosen_route
+            .map(|(route, time_left)| (route, time_left - get_frame_time()))
+            .filter(|&(_, time_left)| time_left > 0.);
+        let mut marked_routes: Vec<(Route, f32, Color)> = turn
+            .map(|turn| {
+                Approach::ALL
+                    .into_iter()
+                    .map(|approach| (Route::new(approach, turn), 3., Color::new(1., 1., 0., 0.6)))
+                    .collect()
+            })
+            .unwrap_or_default();
+        if let Some((route, _)) = chosen_route {
+            marked_routes.push((route, 5., ORANGE));
         }
 
         if is_escaped {
-            statistics.draw_endgame();
+            // Taken once the run ends to write out the rest of the recording
+            if let (Some(recorder), Some(path)) = (recorder.take(), &options.record) {
+                match recorder.finish() {
+                    Ok(()) => println!("Recording written to {}", path),
+                    Err(err) => eprintln!("{}", err),
+                }
+            }
+            let travel_times =
+                final_travel_times.get_or_insert_with(|| simulation.stats().travel_times.summary());
+            simulation.stats().draw_endgame(travel_times);
         } else if is_paused {
             // 3. RENDER / DRAW
             // Draws the game on the screen
-
-            // Draw the cross roads aka the background
-            draw_texture(&cross_road, 0., 0., WHITE);
-            if is_debug_mode {
-                draw_rectangle(
-                    core_intersection.x,
-                    core_intersection.y,
-                    core_intersection.w,
-                    core_intersection.h,
-                    Color::new(0.5, 0.5, 0., 0.1),
-                );
-            }
-
-            //Draw the car_rect
-            cars.iter()
-                .for_each(|car| car.draw_all_components(&car_texture, is_debug_mode));
+            draw_simulation(
+                &simulation,
+                &cross_road,
+                &sprites,
+                is_debug_mode,
+                &marked_routes,
+            );
             // Draw PAUSED TEXT
             draw_text("Press P to continue", 430., 600., 40., BLACK)
         } else {
@@ -86,125 +342,120 @@ async fn main() {
             // Handles any user input that
             // has happened since the last call
 
-            if is_key_pressed(Left) {
-                Car::spawn_if_can(&mut cars, vec!["RU", "RL", "RD"][gen_range(0, 3)], "West");
-            } else if is_key_pressed(Up) {
-                Car::spawn_if_can(&mut cars, vec!["DU", "DL", "DR"][gen_range(0, 3)], "North");
-            } else if is_key_pressed(Down) {
-                Car::spawn_if_can(&mut cars, vec!["UL", "UD", "UR"][gen_range(0, 3)], "South");
-            } else if is_key_pressed(Right) {
-                Car::spawn_if_can(&mut cars, vec!["LU", "LR", "LD"][gen_range(0, 3)], "East");
-            } else if is_key_pressed(KeyCode::R) {
-                is_random = !is_random;
-            } else if is_random {
-                let random_direction = vec!["West", "North", "South", "East"][gen_range(0, 4)];
-                match random_direction {
-                    "West" => {
-                        Car::spawn_if_can(
-                            &mut cars,
-                            vec!["RU", "RL", "RD"][gen_range(0, 3)],
-                            random_direction,
-                        );
-                    }
-                    "North" => {
-                        Car::spawn_if_can(
-                            &mut cars,
-                            vec!["DU", "DL", "DR"][gen_range(0, 3)],
-                            random_direction,
-                        );
-                    }
-                    "South" => {
-                        Car::spawn_if_can(
-                            &mut cars,
-                            vec!["UL", "UD", "UR"][gen_range(0, 3)],
-                            random_direction,
-                        );
-                    }
-                    "East" => {
-                        Car::spawn_if_can(
-                            &mut cars,
-                            vec!["LU", "LR", "LD"][gen_range(0, 3)],
-                            random_direction,
-                        );
+            // Arrow keys spawn a car taking the held turn, or a turn drawn
+            // from the demand if none is held. With E held it is an
+            // emergency vehicle.
+            if let Some(approach) = pressed_approach() {
+                let route = match turn {
+                    Some(turn) => {
+                        let route = Route::new(approach, turn);
+                        chosen_route = Some((route, CHOSEN_ROUTE_TIME));
+                        route
                     }
-                    _ => {}
+                    None => simulation.route_from(approach),
+                };
+                if is_key_down(KeyCode::E) {
+                    simulation.spawn_emergency(route);
+                } else {
+                    simulation.spawn(route);
                 }
+            } else if is_key_pressed(KeyCode::R) {
+                simulation.set_random_spawning(!simulation.random_spawning());
             }
 
             // 2. UPDATE THE STAGE
-            // Advances the game simulation one step
+            // Advances the game simulation in fixed steps covering the
+            // frame time, so the outcome does not depend on the frame rate
             // It runs the AI and game mechanics
-            cars.retain(|car| {
-                if &*car.current_direction == "West" && car.car_rect.x < 100. {
-                    car.check_for_best_or_worst_time(&mut statistics);
-                    statistics.total_cars += 1;
-                    false
-                } else if &*car.current_direction == "North" && car.car_rect.y < 100. {
-                    car.check_for_best_or_worst_time(&mut statistics);
-                    statistics.total_cars += 1;
-                    false
-                } else if &*car.current_direction == "South" && car.car_rect.y > 1050. {
-                    car.check_for_best_or_worst_time(&mut statistics);
-                    statistics.total_cars += 1;
-                    false
-                } else if &*car.current_direction == "East"
-                    && car.car_rect.x + car.car_size.long_edge > 1100.
+            time_accumulator = (time_accumulator + get_frame_time()).min(MAX_FRAME_TIME);
+            while time_accumulator >= TIME_STEP {
+                simulation.step();
+                // A recording that cannot be written is given up
+                if let Some(Err(err)) = recorder
+                    .as_mut()
+                    .map(|recorder| recorder.record(&simulation))
                 {
-                    car.check_for_best_or_worst_time(&mut statistics);
-                    statistics.total_cars += 1;
-                    false
-                } else {
-                    true
+                    eprintln!("{}", err);
+                    recorder = None;
                 }
-            });
-
-            let mut temp_cars = cars.clone();
-            cars.iter()
-                .for_each(|car| car.check_for_collision(&mut temp_cars, &mut statistics));
-
-            let temp_cars = cars.clone();
-            cars.iter_mut()
-                .for_each(|car| car.communicate_with_intersection(&temp_cars, &core_intersection));
-
-            // a method call to update radar positions after moving the car
-
-            let temp_cars = cars.clone();
-            for (car_index, car) in cars.iter_mut().enumerate() {
-                car.update_radar(car_index, &temp_cars);
+                time_accumulator -= TIME_STEP;
             }
 
-            cars.iter_mut().for_each(|car| car.adjust_current_speed());
-
-            // a method call, moves the cars one step based on their direction
-            let mut temp_cars = cars.clone();
-            cars.iter_mut()
-                .filter(|car| !car.waiting_flag)
-                .for_each(|car| car.move_one_step_if_no_collide(&mut temp_cars, &mut statistics));
-
-            let temp_cars = cars.clone();
-            cars.iter_mut().for_each(|car| car.turn_if_can(&temp_cars));
-
             // 3. RENDER / DRAW
             // Draws the game on the screen
+            draw_simulation(
+                &simulation,
+                &cross_road,
+                &sprites,
+                is_debug_mode,
+                &marked_routes,
+            );
 
-            // Draw the cross roads aka the background
-            draw_texture(&cross_road, 0., 0., WHITE);
-            if is_debug_mode {
-                draw_rectangle(
-                    core_intersection.x,
-                    core_intersection.y,
-                    core_intersection.w,
-                    core_intersection.h,
-                    Color::new(0.5, 0.5, 0., 0.1),
+            simulation.stats().draw_ingame();
+            draw_text(
+                format!("Simulated Time: {:.1} sec", simulation.time()).as_str(),
+                915.,
+                100.,
+                32.,
+                RED,
+            );
+            if let Some(scenario) = simulation.scenario_name() {
+                draw_text(
+                    format!("Scenario: {}", scenario).as_str(),
+                    915.,
+                    50.,
+                    32.,
+                    RED,
                 );
             }
+        }
+
+        next_frame().await;
+    }
+}
 
-            //Draw the car_rect
-            cars.iter()
-                .for_each(|car| car.draw_all_components(&car_texture, is_debug_mode));
+// Plays back a recording, with Space to pause, Left and Right to seek,
+// Up and Down to change the speed and a click on the progress bar to jump
+async fn run_replay(recording: Recording) {
+    let mut replay = Replay::new(recording);
+    let mut is_debug_mode = false;
+    let cross_road: Texture2D = load_texture("assets/cross-road.png").await.unwrap();
+    let sprites = Sprites::load().await;
 
-            statistics.draw_ingame();
+    loop {
+        if is_key_pressed(KeyCode::Escape) {
+            std::process::exit(0);
+        }
+        if is_key_pressed(KeyCode::Space) || is_key_pressed(KeyCode::P) {
+            replay.is_paused = !replay.is_paused;
         }
+        if is_key_pressed(KeyCode::D) {
+            is_debug_mode = !is_debug_mode;
+        }
+        if is_key_pressed(Left) {
+            replay.seek(-SEEK_TIME);
+        } else if is_key_pressed(Right) {
+            replay.seek(SEEK_TIME);
+        } else if is_key_pressed(Up) {
+            replay.faster();
+        } else if is_key_pressed(Down) {
+            replay.slower();
+        } else if is_key_pressed(KeyCode::Comma) {
+            replay.seek_frames(-1.);
+        } else if is_key_pressed(KeyCode::Period) {
+            replay.seek_frames(1.);
+        } else if is_key_pressed(KeyCode::Home) {
+            replay.jump_to(0.);
+        } else if is_key_pressed(KeyCode::End) {
+            replay.jump_to(1.);
+        }
+        if is_mouse_button_pressed(MouseButton::Left) {
+            replay.seek_to_mouse(mouse_position().into());
+        }
+        replay.update(get_frame_time().min(MAX_FRAME_TIME));
+
+        draw_texture(&cross_road, 0., 0., WHITE);
+        replay.draw(&sprites, is_debug_mode);
 
         next_frame().await;
     }
@@ -0,0 +1,80 @@
+use macroquad::prelude::*;
+
+// Points sampled along the curved part of a turning path
+const CURVE_SAMPLES: usize = 48;
+
+// The centre line a car follows from its spawn point, as a polyline with
+// the distance from the start stored for every point
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct Path {
+    points: Vec<Vec2>,
+    distances: Vec<f32>,
+}
+
+impl Path {
+    pub fn straight(start: Vec2, direction: Vec2, length: f32) -> Self {
+        Path::from_points(vec![start, start + direction * length])
+    }
+
+    // A path running from `start` towards `corner` and leaving it along
+    // `exit_direction`, the corner rounded off by a quadratic Bézier curve
+    // reaching `radius` back on either side of it
+    pub fn turning(
+        start: Vec2,
+        corner: Vec2,
+        radius: f32,
+        exit_direction: Vec2,
+        exit_length: f32,
+    ) -> Self {
+        let radius = radius.min(start.distance(corner));
+        let curve_start = corner - (corner - start).normalize() * radius;
+        let curve_end = corner + exit_direction * radius;
+
+        let mut points = vec![start];
+        for sample in 0..=CURVE_SAMPLES {
+            let t = sample as f32 / CURVE_SAMPLES as f32;
+            points.push(
+                curve_start * (1. - t) * (1. - t) + corner * 2. * (1. - t) * t + curve_end * t * t,
+            );
+        }
+        points.push(curve_end + exit_direction * exit_length);
+        points.dedup();
+
+        Path::from_points(points)
+    }
+
+    fn from_points(points: Vec<Vec2>) -> Self {
+        let mut distances = vec![0.];
+        for pair in points.windows(2) {
+            distances.push(distances[distances.len() - 1] + pair[0].distance(pair[1]));
+        }
+        Path { points, distances }
+    }
+
+    // Index of the polyline segment holding the point `distance` along
+    fn segment_at(&self, distance: f32) -> usize {
+        let index = self.distances.partition_point(|&start| start <= distance);
+        index.clamp(1, self.points.len() - 1) - 1
+    }
+
+    pub fn point_at(&self, distance: f32) -> Vec2 {
+        let index = self.segment_at(distance);
+        let (from, to) = (self.points[index], self.points[index + 1]);
+        let segment_length = self.distances[index + 1] - self.distances[index];
+        let t = ((distance - self.distances[index]) / segment_length).clamp(0., 1.);
+        from.lerp(to, t)
+    }
+
+    // Heading angle in radians at `distance` along, 0 pointing east
+    pub fn heading_at(&self, distance: f32) -> f32 {
+        let index = self.segment_at(distance);
+        let direction = self.points[index + 1] - self.points[index];
+        direction.y.atan2(direction.x)
+    }
+
+    pub fn draw(&self, thickness: f32, color: Color) {
+        for pair in self.points.windows(2) {
+            draw_line(pair[0].x, pair[0].y, pair[1].x, pair[1].y, thickness, color);
+        }
+    }
+}
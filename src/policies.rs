@@ -0,0 +1,292 @@
+use crate::car::*;
+use crate::conflicts::*;
+use crate::intersection::*;
+use crate::route::*;
+use crate::simulation::TIME_STEP;
+use macroquad::prelude::*;
+use std::cmp::Reverse;
+use std::collections::{HashMap, HashSet};
+
+// Longest a batch keeps letting cars in, in simulated seconds
+pub const BATCH_TIME: f32 = 10.;
+
+// The cars let into the core by an occupancy based policy. A car holds
+// the core for its route until it has left it, and only cars on routes
+// that do not conflict with any of those are let in next to it.
+#[derive(Debug, Clone, Default)]
+pub struct Occupancy {
+    granted: HashMap<u64, Route>,
+}
+
+impl Occupancy {
+    // Forgets the cars that have crossed
+    pub fn update(&mut self, cars: &[Car], core: &Rect) {
+        let crossing: HashSet<u64> = cars
+            .iter()
+            .filter(|car| is_inside(car, core) || is_approaching(car, core))
+            .map(|car| car.id)
+            .collect();
+        self.granted.retain(|id, _| crossing.contains(id));
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.granted.is_empty()
+    }
+
+    pub fn has(&self, car: &Car) -> bool {
+        self.granted.contains_key(&car.id)
+    }
+
+    // Whether a car on `route` may join the cars in the core
+    pub fn allows(&self, route: Route, conflicts: &ConflictMatrix) -> bool {
+        self.granted
+            .values()
+            .all(|&other_route| !conflicts.conflicts(route, other_route))
+    }
+
+    pub fn grant(&mut self, car: &mut Car) {
+        self.granted.insert(car.id, car.route);
+        car.waiting_flag = false;
+    }
+
+    // Lets the waiting cars in going down `order`, each as long as its
+    // route conflicts neither with the core nor with a car earlier in the
+    // order that has to keep waiting
+    pub fn grant_in_order(
+        &mut self,
+        cars: &mut [Car],
+        order: &[usize],
+        conflicts: &ConflictMatrix,
+    ) {
+        let mut held: Vec<Route> = Vec::new();
+        for &index in order {
+            let route = cars[index].route;
+            if self.allows(route, conflicts)
+                && held
+                    .iter()
+                    .all(|&other_route| !conflicts.conflicts(route, other_route))
+            {
+                self.grant(&mut cars[index]);
+            } else {
+                cars[index].waiting_flag = true;
+                held.push(route);
+            }
+        }
+    }
+}
+
+// The step every car waiting at the core was first seen there, so the
+// waiting cars can be served in order of arrival
+#[derive(Debug, Clone, Default)]
+struct ArrivalOrder {
+    arrived: HashMap<u64, u64>,
+}
+
+impl ArrivalOrder {
+    // Indices of the cars approaching the core without being let in yet,
+    // the earliest arrival first
+    fn waiting(
+        &mut self,
+        cars: &[Car],
+        occupancy: &Occupancy,
+        core: &Rect,
+        now: u64,
+    ) -> Vec<usize> {
+        let mut waiting: Vec<usize> = (0..cars.len())
+            .filter(|&index| is_approaching(&cars[index], core) && !occupancy.has(&cars[index]))
+            .collect();
+        self.arrived
+            .retain(|id, _| waiting.iter().any(|&index| cars[index].id == *id));
+        for &index in &waiting {
+            self.arrived.entry(cars[index].id).or_insert(now);
+        }
+        waiting.sort_by_key(|&index| (self.arrived[&cars[index].id], cars[index].id));
+        waiting
+    }
+}
+
+// Cars on each route that have not reached the core yet, whether they are
+// still driving up to it or already waiting there
+fn queue_lengths(cars: &[Car], occupancy: &Occupancy, core: &Rect) -> HashMap<Route, usize> {
+    let mut queues = HashMap::new();
+    for car in cars {
+        if !occupancy.has(car) && !is_inside(car, core) && car.time_in_intersection == 0. {
+            *queues.entry(car.route).or_insert(0) += 1;
+        }
+    }
+    queues
+}
+
+// The rules of the original intersection: a car goes as soon as no car on
+// a conflicting route is in the core, whoever has been waiting longer
+pub struct RuleSet {
+    core: Rect,
+    conflicts: ConflictMatrix,
+    occupancy: Occupancy,
+}
+
+impl RuleSet {
+    pub fn new(core: Rect, conflicts: ConflictMatrix) -> Self {
+        RuleSet {
+            core,
+            conflicts,
+            occupancy: Occupancy::default(),
+        }
+    }
+}
+
+impl IntersectionController for RuleSet {
+    fn update(&mut self, cars: &mut [Car], _now: u64) {
+        self.occupancy.update(cars, &self.core);
+        for car in cars.iter_mut() {
+            if !is_approaching(car, &self.core) || self.occupancy.has(car) {
+                continue;
+            }
+            if self.occupancy.allows(car.route, &self.conflicts) {
+                self.occupancy.grant(car);
+            } else {
+                car.waiting_flag = true;
+            }
+        }
+    }
+}
+
+// Serves the waiting cars in order of arrival at the core. A car may only
+// pass one that arrived earlier if their routes do not conflict.
+pub struct FirstComeFirstServed {
+    core: Rect,
+    conflicts: ConflictMatrix,
+    occupancy: Occupancy,
+    arrivals: ArrivalOrder,
+}
+
+impl FirstComeFirstServed {
+    pub fn new(core: Rect, conflicts: ConflictMatrix) -> Self {
+        FirstComeFirstServed {
+            core,
+            conflicts,
+            occupancy: Occupancy::default(),
+            arrivals: ArrivalOrder::default(),
+        }
+    }
+}
+
+impl IntersectionController for FirstComeFirstServed {
+    fn update(&mut self, cars: &mut [Car], now: u64) {
+        self.occupancy.update(cars, &self.core);
+        let waiting = self
+            .arrivals
+            .waiting(cars, &self.occupancy, &self.core, now);
+        self.occupancy
+            .grant_in_order(cars, &waiting, &self.conflicts);
+    }
+}
+
+// Serves the waiting cars route by route, those with the most cars not
+// through the core yet first and in order of arrival between equal ones
+pub struct LongestQueueFirst {
+    core: Rect,
+    conflicts: ConflictMatrix,
+    occupancy: Occupancy,
+    arrivals: ArrivalOrder,
+}
+
+impl LongestQueueFirst {
+    pub fn new(core: Rect, conflicts: ConflictMatrix) -> Self {
+        LongestQueueFirst {
+            core,
+            conflicts,
+            occupancy: Occupancy::default(),
+            arrivals: ArrivalOrder::default(),
+        }
+    }
+}
+
+impl IntersectionController for LongestQueueFirst {
+    fn update(&mut self, cars: &mut [Car], now: u64) {
+        self.occupancy.update(cars, &self.core);
+        let queues = queue_lengths(cars, &self.occupancy, &self.core);
+        let mut waiting = self
+            .arrivals
+            .waiting(cars, &self.occupancy, &self.core, now);
+        // A stable sort keeps the arrival order between equal queues
+        waiting.sort_by_key(|&index| Reverse(queues[&cars[index].route]));
+        self.occupancy
+            .grant_in_order(cars, &waiting, &self.conflicts);
+    }
+}
+
+// Lets the cars through in batches of routes that do not conflict with
+// each other. A batch is made up from the earliest waiting car on, lets
+// in every car on its routes until they run dry or BATCH_TIME is up, and
+// the next one starts once the core is clear, so platoons pass together.
+pub struct BatchRelease {
+    core: Rect,
+    conflicts: ConflictMatrix,
+    occupancy: Occupancy,
+    arrivals: ArrivalOrder,
+    batch: Vec<Route>,
+    batch_start: u64,
+}
+
+impl BatchRelease {
+    pub fn new(core: Rect, conflicts: ConflictMatrix) -> Self {
+        BatchRelease {
+            core,
+            conflicts,
+            occupancy: Occupancy::default(),
+            arrivals: ArrivalOrder::default(),
+            batch: Vec::new(),
+            batch_start: 0,
+        }
+    }
+}
+
+impl IntersectionController for BatchRelease {
+    fn update(&mut self, cars: &mut [Car], now: u64) {
+        self.occupancy.update(cars, &self.core);
+        let waiting = self
+            .arrivals
+            .waiting(cars, &self.occupancy, &self.core, now);
+
+        let queues = queue_lengths(cars, &self.occupancy, &self.core);
+        let is_over = (now - self.batch_start) as f32 * TIME_STEP > BATCH_TIME
+            || self.batch.iter().all(|route| !queues.contains_key(route));
+        if is_over {
+            self.batch.clear();
+        }
+        if self.batch.is_empty() && self.occupancy.is_empty() {
+            for &index in &waiting {
+                let route = cars[index].route;
+                if self
+                    .batch
+                    .iter()
+                    .all(|&other_route| !self.conflicts.conflicts(route, other_route))
+                {
+                    self.batch.push(route);
+                }
+            }
+            self.batch_start = now;
+        }
+
+        for index in waiting {
+            if self.batch.contains(&cars[index].route) {
+                self.occupancy.grant(&mut cars[index]);
+            } else {
+                cars[index].waiting_flag = true;
+            }
+        }
+    }
+
+    // Lists the routes of the current batch
+    fn draw(&self, _now: u64) {
+        let routes: Vec<String> = self.batch.iter().map(|route| route.code()).collect();
+        draw_text(
+            format!("Batch: {}", routes.join(" ")).as_str(),
+            self.core.x,
+            self.core.y - 8.,
+            24.,
+            BLACK,
+        );
+    }
+}
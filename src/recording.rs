@@ -0,0 +1,429 @@
+use crate::car::*;
+use crate::route::*;
+use crate::scenario::*;
+use crate::simulation::*;
+use crate::vehicles::*;
+use macroquad::prelude::*;
+use serde::{Deserialize, Serialize};
+use std::fs::{self, File};
+use std::io::{BufWriter, Write};
+
+// Playback speeds the replay steps through, as multiples of real time
+pub const REPLAY_SPEEDS: [f32; 7] = [0.125, 0.25, 0.5, 1., 2., 4., 8.];
+// Simulated seconds skipped by one seek
+pub const SEEK_TIME: f32 = 5.;
+// Steps between flushes of a recording file, at most this much of a run
+// that is killed is lost
+const FLUSH_STEPS: u64 = 60;
+// Progress bar at the bottom of the replay window, clicking it seeks
+const PROGRESS_BAR: Rect = Rect {
+    x: 100.,
+    y: 1150.,
+    w: 1000.,
+    h: 16.,
+};
+
+// A run recorded step by step so it can be played back in the window and
+// shared. It is written as JSON Lines while the run goes, see Recorder.
+#[derive(Debug, Clone)]
+pub struct Recording {
+    pub seed: u64,
+    pub controller: String,
+    pub scenario: Option<String>,
+    // Spawns asked for during the run, in the format of scenario files
+    pub spawns: Vec<SpawnEvent>,
+    // The state after every step
+    pub frames: Vec<Frame>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Frame {
+    pub time: f32,
+    pub collisions: u32,
+    pub close_calls: u32,
+    pub cars: Vec<CarState>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct CarState {
+    pub id: u64,
+    pub route: Route,
+    pub x: f32,
+    pub y: f32,
+    pub heading: f32,
+    pub velocity: f32,
+    pub waiting: bool,
+    #[serde(default)]
+    pub emergency: bool,
+    #[serde(default)]
+    pub class: VehicleClass,
+}
+
+// One line of a recording file. The header comes first, then the spawns
+// and frames in the order they happened.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+enum Line {
+    Header {
+        seed: u64,
+        controller: String,
+        scenario: Option<String>,
+    },
+    Spawn(SpawnEvent),
+    Frame(Frame),
+}
+
+// Writes a recording line by line after every step, so it never has to
+// be held in memory and a run cut short keeps what it recorded
+pub struct Recorder {
+    path: String,
+    writer: BufWriter<File>,
+    // Spawns of the spawn log already written
+    spawns: usize,
+    steps: u64,
+}
+
+impl Recorder {
+    pub fn create(path: &str, simulation: &Simulation) -> Result<Self, String> {
+        let file = File::create(path).map_err(|err| format!("cannot write {}: {}", path, err))?;
+        let mut recorder = Recorder {
+            path: path.to_string(),
+            writer: BufWriter::new(file),
+            spawns: 0,
+            steps: 0,
+        };
+        recorder.write(&Line::Header {
+            seed: simulation.seed(),
+            controller: simulation.controller().name().to_string(),
+            scenario: simulation.scenario_name().map(String::from),
+        })?;
+        Ok(recorder)
+    }
+
+    // Writes the spawns since the last step and the current state of the
+    // simulation, called after every step
+    pub fn record(&mut self, simulation: &Simulation) -> Result<(), String> {
+        for spawn in &simulation.spawn_log()[self.spawns..] {
+            self.write(&Line::Spawn(*spawn))?;
+        }
+        self.spawns = simulation.spawn_log().len();
+        self.write(&Line::Frame(Frame {
+            time: simulation.time(),
+            collisions: simulation.stats().collisions,
+            close_calls: simulation.stats().close_calls,
+            cars: simulation
+                .cars()
+                .iter()
+                .map(|car| CarState {
+                    id: car.id,
+                    route: car.route,
+                    x: car.position.x,
+                    y: car.position.y,
+                    heading: car.heading,
+                    velocity: car.velocity,
+                    waiting: car.is_held(),
+                    emergency: car.is_emergency,
+                    class: car.class,
+                })
+                .collect(),
+        }))?;
+        self.steps += 1;
+        if self.steps.is_multiple_of(FLUSH_STEPS) {
+            self.flush()?;
+        }
+        Ok(())
+    }
+
+    // Writes out what is still buffered once the run ends
+    pub fn finish(mut self) -> Result<(), String> {
+        self.flush()
+    }
+
+    fn write(&mut self, line: &Line) -> Result<(), String> {
+        let mut contents = serde_json::to_string(line).unwrap();
+        contents.push('\n');
+        self.writer
+            .write_all(contents.as_bytes())
+            .map_err(|err| format!("cannot write {}: {}", self.path, err))
+    }
+
+    fn flush(&mut self) -> Result<(), String> {
+        self.writer
+            .flush()
+            .map_err(|err| format!("cannot write {}: {}", self.path, err))
+    }
+}
+
+impl Recording {
+    // A last line cut off by a killed run is left out
+    pub fn load(path: &str) -> Result<Self, String> {
+        let contents =
+            fs::read_to_string(path).map_err(|err| format!("cannot read {}: {}", path, err))?;
+        let invalid = |err: String| format!("invalid recording {}: {}", path, err);
+        let mut lines = contents.lines().enumerate();
+        let header = lines
+            .next()
+            .map(|(_, line)| serde_json::from_str(line))
+            .ok_or_else(|| invalid(String::from("it is empty")))?;
+        let Ok(Line::Header {
+            seed,
+            controller,
+            scenario,
+        }) = header
+        else {
+            return Err(invalid(String::from("it does not start with a header")));
+        };
+        let mut recording = Recording {
+            seed,
+            controller,
+            scenario,
+            spawns: Vec::new(),
+            frames: Vec::new(),
+        };
+        let line_count = contents.lines().count();
+        for (index, line) in lines {
+            match serde_json::from_str(line) {
+                Ok(Line::Spawn(spawn)) => recording.spawns.push(spawn),
+                Ok(Line::Frame(frame)) => recording.frames.push(frame),
+                Ok(Line::Header { .. }) => {
+                    return Err(invalid(format!("line {} is a second header", index + 1)));
+                }
+                Err(_) if index + 1 == line_count && !contents.ends_with('\n') => break,
+                Err(err) => return Err(invalid(format!("line {}: {}", index + 1, err))),
+            }
+        }
+        if recording.frames.is_empty() {
+            return Err(format!("recording {} has no frames", path));
+        }
+        Ok(recording)
+    }
+
+    pub fn duration(&self) -> f32 {
+        self.frames.last().map_or(0., |frame| frame.time)
+    }
+}
+
+impl CarState {
+    // Waiting cars are tinted red, and in debug mode every car shows its
+    // id and speed
+    pub fn draw(&self, sprites: &Sprites, debug: bool) {
+        let position = vec2(self.x, self.y);
+        if self.waiting {
+            draw_circle(position.x, position.y, 22., Color::new(1., 0., 0., 0.3));
+        }
+        draw_car_texture(
+            sprites.for_vehicle(self.class, self.emergency),
+            position,
+            self.heading,
+            self.class.sprite_size(),
+        );
+        if debug {
+            draw_text(
+                format!("{} {} {:.0}", self.id, self.route, self.velocity).as_str(),
+                position.x - 20.,
+                position.y - 20.,
+                18.,
+                BLACK,
+            );
+        }
+    }
+}
+
+// Where the playback of a recording stands
+pub struct Replay {
+    pub recording: Recording,
+    // Index of the frame shown, fractional so slow playback moves on
+    // between frames
+    position: f32,
+    speed_index: usize,
+    pub is_paused: bool,
+}
+
+impl Replay {
+    pub fn new(recording: Recording) -> Self {
+        Replay {
+            recording,
+            position: 0.,
+            speed_index: 3,
+            is_paused: false,
+        }
+    }
+
+    pub fn frame(&self) -> &Frame {
+        &self.recording.frames[self.frame_index()]
+    }
+
+    fn frame_index(&self) -> usize {
+        self.position as usize
+    }
+
+    fn last_index(&self) -> f32 {
+        (self.recording.frames.len() - 1) as f32
+    }
+
+    pub fn speed(&self) -> f32 {
+        REPLAY_SPEEDS[self.speed_index]
+    }
+
+    pub fn faster(&mut self) {
+        self.speed_index = (self.speed_index + 1).min(REPLAY_SPEEDS.len() - 1);
+    }
+
+    pub fn slower(&mut self) {
+        self.speed_index = self.speed_index.saturating_sub(1);
+    }
+
+    // Moves on by `dt` real seconds unless paused
+    pub fn update(&mut self, dt: f32) {
+        if !self.is_paused {
+            self.seek_frames(dt * self.speed() / TIME_STEP);
+        }
+    }
+
+    // Jumps `time` simulated seconds forward, or back if negative
+    pub fn seek(&mut self, time: f32) {
+        self.seek_frames(time / TIME_STEP);
+    }
+
+    // Steps single frames, e.g. while paused
+    pub fn seek_frames(&mut self, frames: f32) {
+        self.position = (self.position + frames).clamp(0., self.last_index());
+    }
+
+    // Jumps to a fraction of the way through, 0 being the start and 1
+    // the end
+    pub fn jump_to(&mut self, fraction: f32) {
+        self.position = fraction.clamp(0., 1.) * self.last_index();
+    }
+
+    // Seeks to the point of the progress bar under `mouse`, if it is on it
+    pub fn seek_to_mouse(&mut self, mouse: Vec2) {
+        if PROGRESS_BAR.contains(mouse) {
+            self.jump_to((mouse.x - PROGRESS_BAR.x) / PROGRESS_BAR.w);
+        }
+    }
+
+    pub fn draw(&self, sprites: &Sprites, debug: bool) {
+        let frame = self.frame();
+        for car in &frame.cars {
+            car.draw(sprites, debug);
+        }
+
+        let recording = &self.recording;
+        draw_text(
+            format!(
+                "Replay: {:.1} / {:.1} sec at {}x",
+                frame.time,
+                recording.duration(),
+                self.speed()
+            )
+            .as_str(),
+            15.,
+            50.,
+            32.,
+            RED,
+        );
+        draw_text(
+            format!("Seed: {}", recording.seed).as_str(),
+            15.,
+            100.,
+            32.,
+            RED,
+        );
+        draw_text(
+            format!("Controller: {}", recording.controller).as_str(),
+            15.,
+            150.,
+            32.,
+            RED,
+        );
+        if let Some(scenario) = &recording.scenario {
+            draw_text(
+                format!("Scenario: {}", scenario).as_str(),
+                15.,
+                200.,
+                32.,
+                RED,
+            );
+        }
+        draw_text(
+            format!("Cars: {}", frame.cars.len()).as_str(),
+            915.,
+            100.,
+            32.,
+            RED,
+        );
+        draw_text(
+            format!("Collision: {}", frame.collisions).as_str(),
+            915.,
+            150.,
+            32.,
+            RED,
+        );
+        draw_text(
+            format!("Close Calls: {}", frame.close_calls).as_str(),
+            915.,
+            200.,
+            32.,
+            RED,
+        );
+
+        draw_rectangle(
+            PROGRESS_BAR.x,
+            PROGRESS_BAR.y,
+            PROGRESS_BAR.w,
+            PROGRESS_BAR.h,
+            Color::new(0., 0., 0., 0.3),
+        );
+        draw_rectangle(
+            PROGRESS_BAR.x,
+            PROGRESS_BAR.y,
+            PROGRESS_BAR.w * self.position / self.last_index().max(1.),
+            PROGRESS_BAR.h,
+            RED,
+        );
+        draw_text(
+            "Space pause, Left/Right seek, Up/Down speed, ,/. step a frame, Home/End jump",
+            100.,
+            1140.,
+            24.,
+            BLACK,
+        );
+        if self.is_paused {
+            draw_text("Press Space to continue", 400., 600., 40., BLACK);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::layout::*;
+
+    // A run killed while a line was being written still plays back up to
+    // the last whole frame
+    #[test]
+    fn recording_cut_short_still_loads() {
+        let layout = Layout::load("assets/intersection.toml").unwrap();
+        let mut simulation = Simulation::new(layout, SimulationConfig::default());
+        simulation.set_random_spawning(true);
+        let path = std::env::temp_dir().join("smart-road-recording-test.jsonl");
+        let path = path.to_str().unwrap();
+        let mut recorder = Recorder::create(path, &simulation).unwrap();
+        for _ in 0..600 {
+            simulation.step();
+            recorder.record(&simulation).unwrap();
+        }
+        recorder.finish().unwrap();
+
+        let recording = Recording::load(path).unwrap();
+        assert_eq!(recording.frames.len(), 600);
+        assert_eq!(recording.spawns, simulation.spawn_log());
+
+        let contents = fs::read_to_string(path).unwrap();
+        fs::write(path, &contents[..contents.len() - 10]).unwrap();
+        let recording = Recording::load(path).unwrap();
+        assert_eq!(recording.frames.len(), 599);
+        fs::remove_file(path).unwrap();
+    }
+}
@@ -0,0 +1,208 @@
+use macroquad::prelude::*;
+use serde::{Deserialize, Serialize};
+use std::fmt;
+
+// Heading of a car on the screen, North being up
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Direction {
+    North,
+    South,
+    East,
+    West,
+}
+
+impl Direction {
+    // The compass direction closest to a heading angle, 0 pointing east
+    pub fn from_heading(heading: f32) -> Direction {
+        let vector = Vec2::from_angle(heading);
+        if vector.x.abs() >= vector.y.abs() {
+            if vector.x > 0. {
+                Direction::East
+            } else {
+                Direction::West
+            }
+        } else if vector.y > 0. {
+            Direction::South
+        } else {
+            Direction::North
+        }
+    }
+
+    // Unit vector pointing this way on the screen
+    pub fn vector(self) -> Vec2 {
+        match self {
+            Direction::North => vec2(0., -1.),
+            Direction::South => vec2(0., 1.),
+            Direction::East => vec2(1., 0.),
+            Direction::West => vec2(-1., 0.),
+        }
+    }
+
+    pub fn left(self) -> Direction {
+        match self {
+            Direction::North => Direction::West,
+            Direction::West => Direction::South,
+            Direction::South => Direction::East,
+            Direction::East => Direction::North,
+        }
+    }
+
+    pub fn right(self) -> Direction {
+        match self {
+            Direction::North => Direction::East,
+            Direction::East => Direction::South,
+            Direction::South => Direction::West,
+            Direction::West => Direction::North,
+        }
+    }
+
+    pub fn turned(self, turn: Turn) -> Direction {
+        match turn {
+            Turn::Left => self.left(),
+            Turn::Straight => self,
+            Turn::Right => self.right(),
+        }
+    }
+
+    // The side of the screen a car heading this way drives towards
+    pub fn exit_side(self) -> Approach {
+        match self {
+            Direction::North => Approach::Up,
+            Direction::South => Approach::Down,
+            Direction::East => Approach::Right,
+            Direction::West => Approach::Left,
+        }
+    }
+}
+
+// The side of the screen a car enters the road from, written as its
+// letter, see `letter`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord, Deserialize, Serialize)]
+#[serde(try_from = "String", into = "String")]
+pub enum Approach {
+    Up,
+    Down,
+    Left,
+    Right,
+}
+
+impl Approach {
+    pub const ALL: [Approach; 4] = [
+        Approach::Up,
+        Approach::Down,
+        Approach::Left,
+        Approach::Right,
+    ];
+
+    // Heading of the cars entering from this side
+    pub fn heading(self) -> Direction {
+        match self {
+            Approach::Up => Direction::South,
+            Approach::Down => Direction::North,
+            Approach::Left => Direction::East,
+            Approach::Right => Direction::West,
+        }
+    }
+
+    pub fn letter(self) -> char {
+        match self {
+            Approach::Up => 'U',
+            Approach::Down => 'D',
+            Approach::Left => 'L',
+            Approach::Right => 'R',
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub enum Turn {
+    Left,
+    Straight,
+    Right,
+}
+
+impl Turn {
+    pub const ALL: [Turn; 3] = [Turn::Left, Turn::Straight, Turn::Right];
+}
+
+// The lane a car takes through the intersection, written as a two letter
+// code of the entry and exit sides of the screen, e.g. "RU" enters on the
+// right and leaves at the top
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord, Deserialize, Serialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct Route {
+    pub approach: Approach,
+    pub turn: Turn,
+}
+
+impl Route {
+    pub const ALL: [Route; 12] = {
+        let mut routes = [Route::new(Approach::Up, Turn::Left); 12];
+        let mut index = 0;
+        while index < 12 {
+            routes[index] = Route::new(Approach::ALL[index / 3], Turn::ALL[index % 3]);
+            index += 1;
+        }
+        routes
+    };
+
+    pub const fn new(approach: Approach, turn: Turn) -> Self {
+        Route { approach, turn }
+    }
+
+    pub fn entry_heading(self) -> Direction {
+        self.approach.heading()
+    }
+
+    pub fn exit_heading(self) -> Direction {
+        self.approach.heading().turned(self.turn)
+    }
+
+    pub fn exit(self) -> Approach {
+        self.exit_heading().exit_side()
+    }
+
+    pub fn code(self) -> String {
+        format!("{}{}", self.approach.letter(), self.exit().letter())
+    }
+}
+
+impl TryFrom<String> for Route {
+    type Error = String;
+
+    fn try_from(code: String) -> Result<Self, Self::Error> {
+        Route::ALL
+            .into_iter()
+            .find(|route| route.code() == code)
+            .ok_or_else(|| format!("unknown route {:?}", code))
+    }
+}
+
+impl From<Route> for String {
+    fn from(route: Route) -> Self {
+        route.code()
+    }
+}
+
+impl From<Approach> for String {
+    fn from(approach: Approach) -> Self {
+        approach.letter().to_string()
+    }
+}
+
+impl TryFrom<String> for Approach {
+    type Error = String;
+
+    fn try_from(letter: String) -> Result<Self, Self::Error> {
+        Approach::ALL
+            .into_iter()
+            .find(|approach| approach.letter().to_string() == letter)
+            .ok_or_else(|| format!("unknown approach {:?}", letter))
+    }
+}
+
+impl fmt::Display for Route {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.code())
+    }
+}
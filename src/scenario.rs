@@ -0,0 +1,130 @@
+use crate::car::*;
+use crate::route::*;
+use crate::vehicles::*;
+use serde::{Deserialize, Serialize};
+use std::fs;
+
+// A scripted run, loaded from a scenario file such as
+// assets/scenarios/all-left-turns.toml
+#[derive(Debug, Clone, Deserialize)]
+pub struct Scenario {
+    pub name: Option<String>,
+    // Simulated seconds the run lasts, open ended if not given
+    pub duration: Option<f32>,
+    #[serde(default, rename = "spawn")]
+    pub spawns: Vec<SpawnEvent>,
+}
+
+// A car to spawn at `time` simulated seconds. It takes `route` if given,
+// otherwise a turn from `approach` drawn from the turn weights of the
+// demand; at least one of the two is needed. Without a class it is drawn
+// from the class mix of the demand, and emergency vehicles are cars.
+// Without a speed it cruises at a random one up to the top speed of its
+// class, or at EMERGENCY_SPEED if it is an emergency vehicle.
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize, Serialize)]
+pub struct SpawnEvent {
+    pub time: f32,
+    pub approach: Option<Approach>,
+    pub route: Option<Route>,
+    pub speed: Option<f32>,
+    #[serde(default)]
+    pub emergency: bool,
+    pub class: Option<VehicleClass>,
+}
+
+impl Scenario {
+    pub fn load(path: &str) -> Result<Self, String> {
+        let contents =
+            fs::read_to_string(path).map_err(|err| format!("cannot read {}: {}", path, err))?;
+        Scenario::parse(&contents).map_err(|err| format!("invalid scenario {}: {}", path, err))
+    }
+
+    // The spawn events come out sorted by time, in file order for equal
+    // times
+    pub fn parse(contents: &str) -> Result<Self, String> {
+        let mut scenario: Scenario = toml::from_str(contents).map_err(|err| err.to_string())?;
+        for (index, spawn) in scenario.spawns.iter().enumerate() {
+            let event = format!("spawn {} at {} sec", index + 1, spawn.time);
+            if spawn.time < 0. {
+                return Err(format!("{} is before the start", event));
+            }
+            match (spawn.approach, spawn.route) {
+                (None, None) => return Err(format!("{} needs an approach or a route", event)),
+                (Some(approach), Some(route)) if route.approach != approach => {
+                    return Err(format!(
+                        "{} takes route {}, which does not start at {}",
+                        event,
+                        route,
+                        approach.letter()
+                    ));
+                }
+                _ => (),
+            }
+            if spawn
+                .speed
+                .is_some_and(|speed| speed <= 0. || speed > MAX_SPEED)
+            {
+                return Err(format!(
+                    "{} needs a speed above 0 and up to {}",
+                    event, MAX_SPEED
+                ));
+            }
+        }
+        scenario
+            .spawns
+            .sort_by(|spawn, other| spawn.time.total_cmp(&other.time));
+        Ok(scenario)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Spawns come out in time order, spawns at the same time in file order
+    #[test]
+    fn spawns_are_sorted_by_time() {
+        let scenario = Scenario::parse(
+            r#"
+            [[spawn]]
+            time = 2.0
+            route = "LU"
+
+            [[spawn]]
+            time = 1.0
+            approach = "D"
+            class = "bus"
+
+            [[spawn]]
+            time = 1.0
+            route = "RL"
+            emergency = true
+            "#,
+        )
+        .unwrap();
+        let spawns: Vec<(f32, Option<Approach>)> = scenario
+            .spawns
+            .iter()
+            .map(|spawn| (spawn.time, spawn.approach))
+            .collect();
+        assert_eq!(spawns, [(1., Some(Approach::Down)), (1., None), (2., None)]);
+        assert_eq!(scenario.spawns[0].class, Some(VehicleClass::Bus));
+        assert!(scenario.spawns[1].emergency);
+        assert_eq!(scenario.duration, None);
+    }
+
+    // Spawns that cannot be placed on the road are refused
+    #[test]
+    fn invalid_spawns_are_refused() {
+        for spawn in [
+            "time = -1.0\nroute = \"LU\"",
+            "time = 0.0",
+            "time = 0.0\napproach = \"R\"\nroute = \"LU\"",
+            "time = 0.0\nroute = \"LU\"\nspeed = 0.0",
+            "time = 0.0\nroute = \"LU\"\nspeed = 1000.0",
+        ] {
+            let contents = format!("[[spawn]]\n{}", spawn);
+            assert!(Scenario::parse(&contents).is_err(), "{}", spawn);
+        }
+    }
+}
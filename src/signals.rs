@@ -0,0 +1,420 @@
+use crate::car::*;
+use crate::conflicts::*;
+use crate::intersection::*;
+use crate::layout::*;
+use crate::policies::*;
+use crate::route::*;
+use crate::simulation::TIME_STEP;
+use crate::spatial::*;
+use macroquad::prelude::*;
+use serde::Deserialize;
+use std::fs;
+
+// How far ahead of the core the detectors of the actuated controller see
+// cars coming
+pub const DETECTOR_LENGTH: f32 = 150.;
+
+// Phases and timings of the traffic lights, loaded from a signal plan such
+// as assets/signals/main-street.toml. Times are in simulated seconds.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+pub struct SignalPlan {
+    // Amber after every green, and all red before the next green. All red
+    // lasts until the cars let in have cleared the way of the next phase.
+    #[serde(default = "default_amber")]
+    pub amber: f32,
+    #[serde(default = "default_all_red")]
+    pub all_red: f32,
+    // Actuated control ends a green once no car has been detected on its
+    // routes for this long
+    #[serde(default = "default_gap")]
+    pub gap: f32,
+    #[serde(rename = "phase")]
+    pub phases: Vec<Phase>,
+}
+
+// Routes that get green together, they must not conflict with each other
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+pub struct Phase {
+    pub routes: Vec<Route>,
+    // Green of fixed-time control
+    #[serde(default = "default_green")]
+    pub green: f32,
+    // Shortest and longest green of actuated control
+    #[serde(default = "default_min_green")]
+    pub min_green: f32,
+    #[serde(default = "default_max_green")]
+    pub max_green: f32,
+}
+
+fn default_amber() -> f32 {
+    3.
+}
+
+fn default_all_red() -> f32 {
+    1.5
+}
+
+fn default_gap() -> f32 {
+    2.
+}
+
+fn default_green() -> f32 {
+    15.
+}
+
+fn default_min_green() -> f32 {
+    5.
+}
+
+fn default_max_green() -> f32 {
+    30.
+}
+
+impl SignalPlan {
+    // One phase per approach for its straight on and left turn, with every
+    // right turn that conflicts with neither of them green along
+    pub fn per_approach(conflicts: &ConflictMatrix) -> Self {
+        SignalPlan {
+            amber: default_amber(),
+            all_red: default_all_red(),
+            gap: default_gap(),
+            phases: Approach::ALL
+                .into_iter()
+                .map(|approach| {
+                    let mut routes = vec![
+                        Route::new(approach, Turn::Straight),
+                        Route::new(approach, Turn::Left),
+                    ];
+                    let right_turns = Approach::ALL
+                        .map(|approach| Route::new(approach, Turn::Right))
+                        .into_iter()
+                        .filter(|right_turn| {
+                            routes
+                                .iter()
+                                .all(|route| !conflicts.conflicts(*right_turn, *route))
+                        })
+                        .collect::<Vec<_>>();
+                    routes.extend(right_turns);
+                    Phase {
+                        routes,
+                        green: default_green(),
+                        min_green: default_min_green(),
+                        max_green: default_max_green(),
+                    }
+                })
+                .collect(),
+        }
+    }
+
+    pub fn load(path: &str) -> Result<Self, String> {
+        let contents =
+            fs::read_to_string(path).map_err(|err| format!("cannot read {}: {}", path, err))?;
+        SignalPlan::parse(&contents).map_err(|err| format!("invalid signal plan {}: {}", path, err))
+    }
+
+    pub fn parse(contents: &str) -> Result<Self, String> {
+        let plan: SignalPlan = toml::from_str(contents).map_err(|err| err.to_string())?;
+        if plan.phases.is_empty() {
+            return Err(String::from("there must be at least one phase"));
+        }
+        if plan.amber < 0. || plan.all_red < 0. || plan.gap < 0. {
+            return Err(String::from("amber, all_red and gap must not be negative"));
+        }
+        for (index, phase) in plan.phases.iter().enumerate() {
+            let name = format!("phase {}", index + 1);
+            if phase.routes.is_empty() {
+                return Err(format!("{} has no routes", name));
+            }
+            if phase.green <= 0. || phase.min_green <= 0. {
+                return Err(format!("{} needs a green above 0", name));
+            }
+            if phase.min_green > phase.max_green {
+                return Err(format!("{} has a min_green above its max_green", name));
+            }
+        }
+        Ok(plan)
+    }
+
+    // Checks the plan against the lanes of a layout: no phase may give
+    // green to routes that conflict, and every lane needs a green
+    pub fn check(&self, layout: &Layout, conflicts: &ConflictMatrix) -> Result<(), String> {
+        for (index, phase) in self.phases.iter().enumerate() {
+            for route in &phase.routes {
+                if let Some(other_route) = phase
+                    .routes
+                    .iter()
+                    .find(|other_route| conflicts.conflicts(*route, **other_route))
+                {
+                    return Err(format!(
+                        "phase {} gives green to {} and {}, which conflict",
+                        index + 1,
+                        route,
+                        other_route
+                    ));
+                }
+            }
+        }
+        for lane in &layout.lanes {
+            if !self
+                .phases
+                .iter()
+                .any(|phase| phase.routes.contains(&lane.route))
+            {
+                return Err(format!("route {} never gets green", lane.route));
+            }
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Light {
+    Green,
+    Amber,
+    Red,
+}
+
+impl Light {
+    fn color(self) -> Color {
+        match self {
+            Light::Green => GREEN,
+            Light::Amber => ORANGE,
+            Light::Red => RED,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Interval {
+    Green,
+    Amber,
+    AllRed,
+}
+
+// A signal head just before the core, where the lane of its route enters it
+#[derive(Debug, Clone, Copy)]
+struct SignalHead {
+    route: Route,
+    position: Vec2,
+}
+
+// Traffic lights going through the phases of a SignalPlan, either on fixed
+// times or actuated by the cars its detectors see coming. A car reaching
+// the core on green is let in and may finish crossing whatever the lights
+// do next, every other car waits for its green.
+pub struct SignalController {
+    core: Rect,
+    conflicts: ConflictMatrix,
+    plan: SignalPlan,
+    is_actuated: bool,
+    heads: Vec<SignalHead>,
+    occupancy: Occupancy,
+    phase: usize,
+    // Phase that follows the current one, picked when its green ends
+    upcoming: usize,
+    interval: Interval,
+    interval_start: u64,
+    // Last step a detector saw a car on a route of the current phase
+    last_detection: u64,
+}
+
+impl SignalController {
+    pub fn new(
+        layout: &Layout,
+        conflicts: ConflictMatrix,
+        plan: SignalPlan,
+        is_actuated: bool,
+    ) -> Self {
+        let core = layout.core_intersection();
+        let heads = layout
+            .lanes
+            .iter()
+            .map(|lane| SignalHead {
+                route: lane.route,
+                position: stop_line(lane, &core),
+            })
+            .collect();
+        SignalController {
+            core,
+            conflicts,
+            plan,
+            is_actuated,
+            heads,
+            occupancy: Occupancy::default(),
+            phase: 0,
+            upcoming: 0,
+            interval: Interval::Green,
+            interval_start: 0,
+            last_detection: 0,
+        }
+    }
+
+    pub fn light(&self, route: Route) -> Light {
+        if !self.plan.phases[self.phase].routes.contains(&route) {
+            return Light::Red;
+        }
+        // Routes of the upcoming phase as well stay green through the change
+        let is_kept = self.plan.phases[self.upcoming].routes.contains(&route);
+        match self.interval {
+            Interval::Green => Light::Green,
+            _ if is_kept => Light::Green,
+            Interval::Amber => Light::Amber,
+            Interval::AllRed => Light::Red,
+        }
+    }
+
+    // Whether a detector sees a car coming on a route of `phase`
+    fn has_demand(&self, cars: &[Car], phase: usize) -> bool {
+        let routes = &self.plan.phases[phase].routes;
+        cars.iter().any(|car| {
+            routes.contains(&car.route)
+                && !self.occupancy.has(car)
+                && car.time_in_intersection == 0.
+                && grown(&car.car_rect, DETECTOR_LENGTH).overlaps(&self.core)
+        })
+    }
+
+    // The phase to serve after the current one. Actuated control skips
+    // the phases nobody is waiting for, and None means none is.
+    fn next_phase(&self, cars: &[Car]) -> Option<usize> {
+        let count = self.plan.phases.len();
+        let mut candidates = (1..=count).map(|offset| (self.phase + offset) % count);
+        if self.is_actuated {
+            candidates.find(|&phase| self.has_demand(cars, phase))
+        } else {
+            candidates.next()
+        }
+    }
+
+    fn is_green_over(&self, cars: &[Car], elapsed: f32, now: u64) -> bool {
+        let phase = &self.plan.phases[self.phase];
+        if !self.is_actuated {
+            return elapsed >= phase.green;
+        }
+        let since_detection = (now - self.last_detection) as f32 * TIME_STEP;
+        let is_gapped_out = elapsed >= phase.min_green && since_detection >= self.plan.gap;
+        // Without anyone waiting elsewhere the lights rest on green
+        (is_gapped_out || elapsed >= phase.max_green) && self.next_phase(cars).is_some()
+    }
+
+    fn start(&mut self, interval: Interval, now: u64) {
+        self.interval = interval;
+        self.interval_start = now;
+    }
+}
+
+impl IntersectionController for SignalController {
+    fn update(&mut self, cars: &mut [Car], now: u64) {
+        self.occupancy.update(cars, &self.core);
+        if self.has_demand(cars, self.phase) {
+            self.last_detection = now;
+        }
+
+        let elapsed = (now - self.interval_start) as f32 * TIME_STEP;
+        match self.interval {
+            Interval::Green if self.is_green_over(cars, elapsed, now) => {
+                self.upcoming = self.next_phase(cars).unwrap_or(self.phase);
+                self.start(Interval::Amber, now);
+            }
+            Interval::Amber if elapsed >= self.plan.amber => self.start(Interval::AllRed, now),
+            Interval::AllRed if elapsed >= self.plan.all_red => {
+                let is_clear = self.plan.phases[self.upcoming]
+                    .routes
+                    .iter()
+                    .all(|&route| self.occupancy.allows(route, &self.conflicts));
+                if is_clear {
+                    self.phase = self.upcoming;
+                    self.last_detection = now;
+                    self.start(Interval::Green, now);
+                }
+            }
+            _ => (),
+        }
+
+        for car in cars.iter_mut() {
+            if !is_approaching(car, &self.core) || self.occupancy.has(car) {
+                continue;
+            }
+            if self.light(car.route) == Light::Green {
+                self.occupancy.grant(car);
+            } else {
+                car.waiting_flag = true;
+            }
+        }
+    }
+
+    fn draw_signals(&self) {
+        for head in &self.heads {
+            draw_rectangle(
+                head.position.x - 8.,
+                head.position.y - 8.,
+                16.,
+                16.,
+                DARKGRAY,
+            );
+            draw_circle(
+                head.position.x,
+                head.position.y,
+                6.,
+                self.light(head.route).color(),
+            );
+        }
+    }
+}
+
+// The point where the centre line of a lane enters the core, moved back a
+// little so the signal head sits just outside it
+fn stop_line(lane: &Lane, core: &Rect) -> Vec2 {
+    let mut distance = 0.;
+    while !core.contains(lane.path.point_at(distance)) && distance < 2000. {
+        distance += 1.;
+    }
+    lane.path.point_at(distance) - lane.route.entry_heading().vector() * 10.
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // The shipped plan and the default one pass the check, a phase with
+    // routes that conflict or a lane without a green do not
+    #[test]
+    fn check_finds_conflicting_phases_and_routes_without_green() {
+        let layout = Layout::load("assets/intersection.toml").unwrap();
+        let conflicts = ConflictMatrix::from_layout(&layout);
+        let main_street = SignalPlan::load("assets/signals/main-street.toml").unwrap();
+        assert_eq!(main_street.check(&layout, &conflicts), Ok(()));
+        let per_approach = SignalPlan::per_approach(&conflicts);
+        assert_eq!(per_approach.check(&layout, &conflicts), Ok(()));
+
+        let crossing = SignalPlan::parse("[[phase]]\nroutes = [\"LR\", \"DU\"]").unwrap();
+        let err = crossing.check(&layout, &conflicts).unwrap_err();
+        assert!(err.contains("conflict"), "{}", err);
+        let partial = SignalPlan::parse("[[phase]]\nroutes = [\"LR\"]").unwrap();
+        let err = partial.check(&layout, &conflicts).unwrap_err();
+        assert!(err.contains("never gets green"), "{}", err);
+    }
+
+    // Through amber and all red, routes the upcoming phase gives green to
+    // as well keep theirs
+    #[test]
+    fn routes_of_the_upcoming_phase_stay_green_through_the_change() {
+        let layout = Layout::load("assets/intersection.toml").unwrap();
+        let conflicts = ConflictMatrix::from_layout(&layout);
+        let plan = SignalPlan::load("assets/signals/main-street.toml").unwrap();
+        let mut signals = SignalController::new(&layout, conflicts, plan, false);
+        signals.upcoming = 1;
+        let kept = Route::new(Approach::Right, Turn::Right);
+        let ending = Route::new(Approach::Left, Turn::Straight);
+        let starting = Route::new(Approach::Right, Turn::Straight);
+        for (interval, light) in [
+            (Interval::Amber, Light::Amber),
+            (Interval::AllRed, Light::Red),
+        ] {
+            signals.interval = interval;
+            assert_eq!(signals.light(kept), Light::Green);
+            assert_eq!(signals.light(ending), light);
+            assert_eq!(signals.light(starting), Light::Red);
+        }
+    }
+}
@@ -0,0 +1,431 @@
+use crate::arrivals::*;
+use crate::car::*;
+use crate::close_calls::*;
+use crate::conflicts::*;
+use crate::intersection::*;
+use crate::layout::*;
+use crate::route::*;
+use crate::scenario::*;
+use crate::signals::*;
+use crate::spatial::*;
+use crate::stats::*;
+use crate::trips::*;
+use crate::vehicles::*;
+use macroquad::prelude::*;
+use std::collections::{BTreeMap, VecDeque};
+
+// Length of one simulation step in simulated seconds
+pub const TIME_STEP: f32 = 1. / 60.;
+
+// Tunables of a simulation run
+#[derive(Debug, Clone, PartialEq)]
+pub struct SimulationConfig {
+    // Cars closer to each other than this count as a close call
+    pub safety_distance: f32,
+    // Seeds every random draw of the run, so a seed and the same spawn
+    // inputs reproduce the run exactly
+    pub seed: u64,
+    // Arrivals while random spawning is on, DEFAULT_RATE from every
+    // approach if it has none
+    pub demand: Demand,
+    pub controller: Controller,
+    // Phases and timings of the signalised controllers, one phase per
+    // approach if there is none, see SignalPlan::per_approach
+    pub signals: Option<SignalPlan>,
+}
+
+impl Default for SimulationConfig {
+    fn default() -> Self {
+        SimulationConfig {
+            safety_distance: STANDSTILL_GAP * 0.75,
+            seed: 0,
+            demand: Demand::default(),
+            controller: Controller::Reservation,
+            signals: None,
+        }
+    }
+}
+
+// A car waiting in the entry queue of its lane for room to spawn
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct PendingCar {
+    pub requested_time: f32,
+    pub speed: f32,
+    pub is_emergency: bool,
+    pub class: VehicleClass,
+}
+
+// The intersection model without any windowing or drawing, so it can be
+// stepped from the macroquad frontend as well as from headless runs.
+pub struct Simulation {
+    cars: Vec<Car>,
+    stats: Stats,
+    // Trips of the cars that have left the road, in the order they left
+    trips: Vec<Trip>,
+    layout: Layout,
+    conflicts: ConflictMatrix,
+    intersection: Box<dyn IntersectionController>,
+    close_calls: CloseCallDetector,
+    grid: SpatialGrid,
+    // Spawns the arrivals of the demand while on
+    random_spawning: bool,
+    seed: u64,
+    controller: Controller,
+    arrivals: ArrivalGenerator,
+    // Scenario spawns still to come, the next one last
+    scripted_spawns: Vec<SpawnEvent>,
+    scenario_name: Option<String>,
+    // Every spawn asked for so far, whether by hand, by the demand or by
+    // the scenario
+    spawn_log: Vec<SpawnEvent>,
+    // Cars waiting to enter each lane, the next one first
+    entry_queues: BTreeMap<Route, VecDeque<PendingCar>>,
+    // Cars spawned so far, which is also the id of the next one
+    spawned: u64,
+    steps: u64,
+}
+
+impl Simulation {
+    // The simulation draws from macroquad's global generator, which is
+    // reseeded here, so only one simulation should run at a time
+    pub fn new(layout: Layout, config: SimulationConfig) -> Self {
+        rand::srand(config.seed);
+        let conflicts = ConflictMatrix::from_layout(&layout);
+        let intersection = config
+            .controller
+            .build(&layout, &conflicts, &config.signals);
+        Simulation {
+            cars: Vec::new(),
+            stats: Stats::new(),
+            trips: Vec::new(),
+            conflicts,
+            intersection,
+            close_calls: CloseCallDetector::new(config.safety_distance),
+            layout,
+            grid: SpatialGrid::new(),
+            random_spawning: false,
+            seed: config.seed,
+            controller: config.controller,
+            arrivals: ArrivalGenerator::new(if config.demand.approaches.is_empty() {
+                Demand {
+                    classes: config.demand.classes,
+                    ..Demand::uniform(DEFAULT_RATE)
+                }
+            } else {
+                config.demand
+            }),
+            scripted_spawns: Vec::new(),
+            scenario_name: None,
+            spawn_log: Vec::new(),
+            entry_queues: BTreeMap::new(),
+            spawned: 0,
+            steps: 0,
+        }
+    }
+
+    pub fn cars(&self) -> &[Car] {
+        &self.cars
+    }
+
+    pub fn stats(&self) -> &Stats {
+        &self.stats
+    }
+
+    pub fn trips(&self) -> &[Trip] {
+        &self.trips
+    }
+
+    pub fn layout(&self) -> &Layout {
+        &self.layout
+    }
+
+    pub fn intersection(&self) -> &dyn IntersectionController {
+        self.intersection.as_ref()
+    }
+
+    pub fn conflicts(&self) -> &ConflictMatrix {
+        &self.conflicts
+    }
+
+    // Number of steps taken since the start of the run
+    pub fn steps(&self) -> u64 {
+        self.steps
+    }
+
+    // Simulated seconds elapsed since the start of the run
+    pub fn time(&self) -> f32 {
+        self.steps as f32 * TIME_STEP
+    }
+
+    pub fn seed(&self) -> u64 {
+        self.seed
+    }
+
+    pub fn controller(&self) -> Controller {
+        self.controller
+    }
+
+    pub fn scenario_name(&self) -> Option<&str> {
+        self.scenario_name.as_deref()
+    }
+
+    pub fn spawn_log(&self) -> &[SpawnEvent] {
+        &self.spawn_log
+    }
+
+    pub fn random_spawning(&self) -> bool {
+        self.random_spawning
+    }
+
+    pub fn set_random_spawning(&mut self, random_spawning: bool) {
+        self.random_spawning = random_spawning;
+    }
+
+    // Spawns a vehicle of a class drawn from the class mix, cruising at a
+    // random speed
+    pub fn spawn(&mut self, route: Route) {
+        let class = self.arrivals.class();
+        self.queue_vehicle(route, class, class.random_speed(), false);
+    }
+
+    // An emergency vehicle jumps the entry queue of its lane, only behind
+    // the emergency vehicles already in it
+    pub fn spawn_emergency(&mut self, route: Route) {
+        self.queue_vehicle(route, VehicleClass::Car, EMERGENCY_SPEED, true);
+    }
+
+    // The vehicle joins the entry queue of its lane and enters the road as
+    // soon as there is room. Routes missing from the layout are ignored.
+    fn queue_vehicle(&mut self, route: Route, class: VehicleClass, speed: f32, is_emergency: bool) {
+        if self.layout.lane(route).is_none() {
+            return;
+        }
+        self.stats.requested_cars += 1;
+        let requested_time = self.time();
+        self.spawn_log.push(SpawnEvent {
+            time: requested_time,
+            approach: Some(route.approach),
+            route: Some(route),
+            speed: Some(speed),
+            emergency: is_emergency,
+            class: Some(class),
+        });
+        let queue = self.entry_queues.entry(route).or_default();
+        let position = if is_emergency {
+            queue
+                .iter()
+                .take_while(|pending| pending.is_emergency)
+                .count()
+        } else {
+            queue.len()
+        };
+        queue.insert(
+            position,
+            PendingCar {
+                requested_time,
+                speed,
+                is_emergency,
+                class,
+            },
+        );
+        self.release_queue(route);
+    }
+
+    // Cars waiting to enter the lane of `route`
+    pub fn queue_length(&self, route: Route) -> usize {
+        self.entry_queues.get(&route).map_or(0, VecDeque::len)
+    }
+
+    // Lets the cars at the head of the entry queue of `route` spawn while
+    // there is room on the lane
+    fn release_queue(&mut self, route: Route) {
+        let now = self.time();
+        let (Some(lane), Some(queue)) =
+            (self.layout.lane(route), self.entry_queues.get_mut(&route))
+        else {
+            return;
+        };
+        while let Some(pending) = queue.front() {
+            if !Car::spawn_if_can(
+                &mut self.cars,
+                &self.grid,
+                lane,
+                self.spawned,
+                now,
+                pending.speed,
+                pending.class,
+            ) {
+                break;
+            }
+            let car = self.cars.last_mut().unwrap();
+            car.entry_delay = now - pending.requested_time;
+            car.is_emergency = pending.is_emergency;
+            self.stats.entry_delays.add(car.entry_delay);
+            self.spawned += 1;
+            queue.pop_front();
+        }
+    }
+
+    // Plays back the spawn events of the scenario from the current time on
+    pub fn set_scenario(&mut self, scenario: &Scenario) {
+        let now = self.time();
+        self.scenario_name = scenario.name.clone();
+        self.scripted_spawns = scenario
+            .spawns
+            .iter()
+            .map(|spawn| SpawnEvent {
+                time: spawn.time + now,
+                ..*spawn
+            })
+            .rev()
+            .collect();
+    }
+
+    // A route from `approach` taking a turn drawn from the turn weights of
+    // the demand
+    pub fn route_from(&self, approach: Approach) -> Route {
+        self.arrivals.route_from(approach)
+    }
+
+    // Advances the simulation by one fixed TIME_STEP
+    pub fn step(&mut self) {
+        let now = self.time();
+        let routes: Vec<Route> = self.entry_queues.keys().copied().collect();
+        for route in routes {
+            self.release_queue(route);
+        }
+        if self.random_spawning {
+            for route in self.arrivals.arrivals(now) {
+                self.spawn(route);
+            }
+        }
+        while let Some(spawn) = self.scripted_spawns.pop_if(|spawn| spawn.time <= now) {
+            let route = spawn
+                .route
+                .unwrap_or_else(|| self.arrivals.route_from(spawn.approach.unwrap()));
+            let class = match spawn.class {
+                _ if spawn.emergency => VehicleClass::Car,
+                Some(class) => class,
+                None => self.arrivals.class(),
+            };
+            let speed = match spawn.speed {
+                Some(speed) => speed,
+                None if spawn.emergency => EMERGENCY_SPEED,
+                None => class.random_speed(),
+            };
+            self.queue_vehicle(route, class, speed, spawn.emergency);
+        }
+
+        self.steps += 1;
+        let now = self.time();
+        let despawn = self.layout.despawn;
+        let statistics = &mut self.stats;
+        let trips = &mut self.trips;
+        self.cars.retain(|car| {
+            if car.has_left_the_road(&despawn) {
+                car.check_for_best_or_worst_time(now, statistics);
+                let trip = Trip::new(car, now);
+                statistics.record_trip(&trip);
+                trips.push(trip);
+                statistics.total_cars += 1;
+                false
+            } else {
+                true
+            }
+        });
+
+        // index the cars once, every query below goes through the grid
+        self.grid.rebuild(&self.cars);
+
+        for (car_index, car) in self.cars.iter().enumerate() {
+            car.check_for_collision(car_index, &self.cars, &self.grid, &mut self.stats);
+        }
+
+        self.intersection.update(&mut self.cars, self.steps);
+        give_way_to_emergencies(
+            &mut self.cars,
+            &self.layout.core_intersection(),
+            &self.conflicts,
+        );
+
+        // every radar is scanned against the positions and radars of the
+        // previous step before any of them is replaced
+        let radars: Vec<Rect> = (0..self.cars.len())
+            .map(|car_index| self.cars[car_index].scan_radar(car_index, &self.cars, &self.grid))
+            .collect();
+        for (car, radar) in self.cars.iter_mut().zip(radars) {
+            car.radar = radar;
+        }
+
+        self.cars
+            .iter_mut()
+            .for_each(|car| car.adjust_current_speed(TIME_STEP));
+
+        // moves the cars one step based on their direction
+        for car_index in 0..self.cars.len() {
+            if !self.cars[car_index].is_held() {
+                Car::move_one_step_if_no_collide(&mut self.cars, car_index, &self.grid, TIME_STEP);
+            }
+        }
+
+        let core_intersection = self.layout.core_intersection();
+        self.cars
+            .iter_mut()
+            .for_each(|car| car.track_trip(TIME_STEP, &core_intersection));
+
+        self.close_calls
+            .update(&mut self.cars, &self.grid, &mut self.stats);
+
+        let cars_in_core = self
+            .cars
+            .iter()
+            .filter(|car| car.car_rect.overlaps(&core_intersection))
+            .count();
+        let queued_cars: usize = self.entry_queues.values().map(VecDeque::len).sum();
+        self.stats
+            .update_time(now, cars_in_core as u32, queued_cars as u32);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Vehicles requested on a lane without room wait in the entry queue of
+    // that lane only, and enter it in turn as it clears, an emergency
+    // vehicle ahead of the cars queued before it
+    #[test]
+    fn queued_vehicles_enter_their_lane_in_turn() {
+        let layout = Layout::load("assets/intersection.toml").unwrap();
+        let mut simulation = Simulation::new(layout, SimulationConfig::default());
+        let route = Route::new(Approach::Left, Turn::Straight);
+        let other_route = Route::new(Approach::Right, Turn::Straight);
+        simulation.spawn(route);
+        simulation.spawn(route);
+        simulation.spawn(route);
+        simulation.spawn_emergency(route);
+        simulation.spawn(other_route);
+        assert_eq!(simulation.cars().len(), 2);
+        assert_eq!(simulation.queue_length(route), 3);
+        assert_eq!(simulation.queue_length(other_route), 0);
+
+        for _ in 0..60 * 30 {
+            if simulation.queue_length(route) == 0 {
+                break;
+            }
+            simulation.step();
+        }
+        assert_eq!(simulation.queue_length(route), 0);
+        let cars: Vec<&Car> = simulation
+            .cars()
+            .iter()
+            .filter(|car| car.route == route)
+            .collect();
+        assert_eq!(cars.len(), 4);
+        assert!(cars[1].is_emergency);
+        assert_eq!(cars[0].entry_delay, 0.);
+        assert!(cars
+            .windows(2)
+            .all(|pair| pair[0].entry_delay < pair[1].entry_delay));
+    }
+}
@@ -0,0 +1,68 @@
+use crate::car::*;
+use macroquad::prelude::*;
+use std::collections::HashMap;
+
+// Edge length of the square grid cells
+pub const CELL_SIZE: f32 = 64.;
+
+// Uniform grid over the road mapping cells to the indices of the cars
+// whose body or radar reaches into them. It is rebuilt once per step, so
+// lookups are only as fresh as the car list it was built from.
+#[derive(Debug, Default)]
+pub struct SpatialGrid {
+    cells: HashMap<(i32, i32), Vec<usize>>,
+    indexed: usize,
+}
+
+impl SpatialGrid {
+    pub fn new() -> Self {
+        SpatialGrid::default()
+    }
+
+    pub fn rebuild(&mut self, cars: &[Car]) {
+        self.cells.values_mut().for_each(|cell| cell.clear());
+        for (index, car) in cars.iter().enumerate() {
+            for cell in cells_under(&car.car_rect.combine_with(car.radar)) {
+                self.cells.entry(cell).or_default().push(index);
+            }
+        }
+        self.indexed = cars.len();
+    }
+
+    // Number of cars the grid was built from, cars pushed to the list
+    // after that are not in it
+    pub fn indexed(&self) -> usize {
+        self.indexed
+    }
+
+    // Indices of the cars that may reach into `area`, in ascending order
+    pub fn query(&self, area: &Rect) -> Vec<usize> {
+        let mut indices: Vec<usize> = cells_under(area)
+            .filter_map(|cell| self.cells.get(&cell))
+            .flatten()
+            .copied()
+            .collect();
+        indices.sort_unstable();
+        indices.dedup();
+        indices
+    }
+}
+
+fn cells_under(area: &Rect) -> impl Iterator<Item = (i32, i32)> {
+    let first_column = (area.x / CELL_SIZE).floor() as i32;
+    let last_column = (area.right() / CELL_SIZE).floor() as i32;
+    let first_row = (area.y / CELL_SIZE).floor() as i32;
+    let last_row = (area.bottom() / CELL_SIZE).floor() as i32;
+    (first_row..=last_row)
+        .flat_map(move |row| (first_column..=last_column).map(move |column| (column, row)))
+}
+
+// The rectangle grown by `margin` on every side
+pub fn grown(area: &Rect, margin: f32) -> Rect {
+    Rect::new(
+        area.x - margin,
+        area.y - margin,
+        area.w + 2. * margin,
+        area.h + 2. * margin,
+    )
+}
@@ -1,16 +1,200 @@
+use crate::route::*;
+use crate::trips::*;
+use crate::vehicles::*;
 use macroquad::prelude::*;
-#[derive(Debug, PartialEq, Clone, Copy)]
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::fmt;
+#[derive(Debug, PartialEq, Clone, Serialize)]
 pub struct Stats {
     pub total_cars: u32,
-    pub best_time: f32,
+    // The shortest time and lowest velocity are None until a car has left
+    // the road
+    pub best_time: Option<f32>,
     pub worst_time: f32,
     pub best_velocity: f32,
-    pub worst_velocity: f32,
+    pub worst_velocity: Option<f32>,
+    pub top_speed: f32,
     pub close_calls: u32,
     pub collisions: u32,
+    // Travel times of the cars that left the road, of all of them and
+    // split by route, by approach and by vehicle class
+    pub travel_times: Durations,
+    pub per_route: BTreeMap<Route, Durations>,
+    pub per_approach: BTreeMap<Approach, Durations>,
+    pub per_class: BTreeMap<VehicleClass, Durations>,
+    // Cars that left the road per simulated minute
+    pub throughput: f32,
+    // Most cars inside the core intersection at the same time
+    pub max_in_core: u32,
+    // Cars asked to spawn, whether they entered the road yet or not
+    pub requested_cars: u32,
+    // Cars waiting in the entry queues of the lanes, now and at most
+    pub queued_cars: u32,
+    pub max_queued_cars: u32,
+    // Time the cars that entered the road waited in an entry queue
+    pub entry_delays: Durations,
+    // Time the cars that left the road spent standing on it
+    pub waiting_times: Durations,
+    // Times the cars that left the road came to a stop, in all and the
+    // most any one of them did
+    pub stops: u32,
+    pub max_stops: u32,
+    // Cars that left the road without ever stopping
+    pub unstopped_cars: u32,
+    // Time the emergency vehicles that left the road lost against driving
+    // the whole way at their cruising speed
+    pub emergency_delays: Durations,
+    pub simulated_time: f32,
+}
+
+// Distribution of durations in simulated seconds, such as travel times.
+// Adding one is cheap, the percentiles are only worked out when asked for
+// and are interpolated between the closest samples.
+#[derive(Debug, PartialEq, Clone, Default)]
+pub struct Durations {
+    pub count: u32,
+    sum: f64,
+    // Every duration so far, in the order they were added
+    samples: Vec<f32>,
+}
+
+// The figures of Durations that go into reports
+#[derive(Debug, PartialEq, Clone, Copy, Default, Serialize)]
+pub struct Summary {
+    pub count: u32,
+    pub mean: f32,
+    pub median: f32,
+    pub p90: f32,
+    pub p95: f32,
+    pub p99: f32,
+}
+
+impl Durations {
+    pub fn add(&mut self, duration: f32) {
+        self.samples.push(duration);
+        self.sum += duration as f64;
+        self.count += 1;
+    }
+
+    // 0 while there are no samples
+    pub fn mean(&self) -> f32 {
+        if self.count == 0 {
+            return 0.;
+        }
+        (self.sum / self.count as f64) as f32
+    }
+
+    // Percentiles are 0 while there are no samples
+    pub fn summary(&self) -> Summary {
+        let sorted = self.sorted();
+        Summary {
+            count: self.count,
+            mean: self.mean(),
+            median: percentile(&sorted, 50.),
+            p90: percentile(&sorted, 90.),
+            p95: percentile(&sorted, 95.),
+            p99: percentile(&sorted, 99.),
+        }
+    }
+
+    fn sorted(&self) -> Vec<f32> {
+        let mut sorted = self.samples.clone();
+        sorted.sort_by(f32::total_cmp);
+        sorted
+    }
+}
+
+// Reports show the summary rather than every sample
+impl Serialize for Durations {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        self.summary().serialize(serializer)
+    }
+}
+
+fn percentile(sorted: &[f32], percent: f32) -> f32 {
+    if sorted.is_empty() {
+        return 0.;
+    }
+    let rank = percent / 100. * (sorted.len() - 1) as f32;
+    let (below, above) = (rank.floor() as usize, rank.ceil() as usize);
+    let fraction = rank - below as f32;
+    sorted[below] + (sorted[above] - sorted[below]) * fraction
 }
 
 impl Stats {
+    pub fn new() -> Self {
+        Stats {
+            total_cars: 0,
+            best_time: None,
+            worst_time: 0.,
+            best_velocity: 0.,
+            worst_velocity: None,
+            top_speed: 0.,
+            collisions: 0,
+            close_calls: 0,
+            travel_times: Durations::default(),
+            per_route: BTreeMap::new(),
+            per_approach: BTreeMap::new(),
+            per_class: BTreeMap::new(),
+            throughput: 0.,
+            max_in_core: 0,
+            requested_cars: 0,
+            queued_cars: 0,
+            max_queued_cars: 0,
+            entry_delays: Durations::default(),
+            waiting_times: Durations::default(),
+            stops: 0,
+            max_stops: 0,
+            unstopped_cars: 0,
+            emergency_delays: Durations::default(),
+            simulated_time: 0.,
+        }
+    }
+
+    pub fn record_trip(&mut self, trip: &Trip) {
+        self.travel_times.add(trip.travel_time);
+        self.waiting_times.add(trip.waiting_time);
+        self.stops += trip.stops;
+        self.max_stops = self.max_stops.max(trip.stops);
+        if trip.stops == 0 {
+            self.unstopped_cars += 1;
+        }
+        if trip.emergency {
+            self.emergency_delays.add(trip.delay);
+        }
+        self.per_route
+            .entry(trip.route)
+            .or_default()
+            .add(trip.travel_time);
+        self.per_approach
+            .entry(trip.route.approach)
+            .or_default()
+            .add(trip.travel_time);
+        self.per_class
+            .entry(trip.class)
+            .or_default()
+            .add(trip.travel_time);
+    }
+
+    // Called once per step with the number of cars inside the core and
+    // waiting to enter the road
+    pub fn update_time(&mut self, now: f32, cars_in_core: u32, queued_cars: u32) {
+        self.simulated_time = now;
+        self.throughput = self.total_cars as f32 / (now / 60.);
+        self.max_in_core = self.max_in_core.max(cars_in_core);
+        self.queued_cars = queued_cars;
+        self.max_queued_cars = self.max_queued_cars.max(queued_cars);
+    }
+
+    // Mean stops of the cars that left the road, 0 before any did
+    pub fn mean_stops(&self) -> f32 {
+        if self.travel_times.count == 0 {
+            return 0.;
+        }
+        self.stops as f32 / self.travel_times.count as f32
+    }
+
     pub fn draw_ingame(&self) {
         draw_text(format!("FPS: {}", get_fps()).as_str(), 15., 100., 32., RED);
         draw_text(
@@ -21,7 +205,7 @@ impl Stats {
             RED,
         );
         draw_text(
-            format!("Best Time: {} sec", self.best_time).as_str(),
+            format!("Best Time: {}", or_none(self.best_time, " sec")).as_str(),
             15.,
             200.,
             32.,
@@ -42,12 +226,26 @@ impl Stats {
             RED,
         );
         draw_text(
-            format!("Worst Velocity: {}", self.worst_velocity).as_str(),
+            format!("Worst Velocity: {}", or_none(self.worst_velocity, "")).as_str(),
             15.,
             350.,
             32.,
             RED,
         );
+        draw_text(
+            format!("Top Speed: {}", self.top_speed).as_str(),
+            15.,
+            400.,
+            32.,
+            RED,
+        );
+        draw_text(
+            format!("Mean Time: {} sec", self.travel_times.mean()).as_str(),
+            15.,
+            450.,
+            32.,
+            RED,
+        );
         draw_text(
             format!("Collision: {}", self.collisions).as_str(),
             915.,
@@ -62,9 +260,46 @@ impl Stats {
             32.,
             RED,
         );
+        draw_text(
+            format!("Throughput: {:.1} cars/min", self.throughput).as_str(),
+            915.,
+            250.,
+            32.,
+            RED,
+        );
+        draw_text(
+            format!("Max In Core: {}", self.max_in_core).as_str(),
+            915.,
+            300.,
+            32.,
+            RED,
+        );
+        draw_text(
+            format!("Queued Cars: {}", self.queued_cars).as_str(),
+            915.,
+            350.,
+            32.,
+            RED,
+        );
+        draw_text(
+            format!("Stops Per Car: {:.2}", self.mean_stops()).as_str(),
+            915.,
+            400.,
+            32.,
+            RED,
+        );
+        draw_text(
+            format!("Emergency Delay: {:.2} sec", self.emergency_delays.mean()).as_str(),
+            915.,
+            450.,
+            32.,
+            RED,
+        );
     }
 
-    pub fn draw_endgame(&self) {
+    // `travel_times` is the summary of the travel times, worked out once
+    // when the run ended rather than on every frame
+    pub fn draw_endgame(&self, travel_times: &Summary) {
         draw_text(format!("FPS: {}", get_fps()).as_str(), 100., 100., 32., RED);
         draw_text("Statistics", 500., 250., 46., WHITE);
         draw_text(
@@ -75,7 +310,7 @@ impl Stats {
             RED,
         );
         draw_text(
-            format!("Best Time: {} sec", self.best_time).as_str(),
+            format!("Best Time: {}", or_none(self.best_time, " sec")).as_str(),
             450.,
             350.,
             32.,
@@ -96,12 +331,40 @@ impl Stats {
             RED,
         );
         draw_text(
-            format!("Worst Velocity: {}", self.worst_velocity).as_str(),
+            format!("Worst Velocity: {}", or_none(self.worst_velocity, "")).as_str(),
             450.,
             500.,
             32.,
             RED,
         );
+        draw_text(
+            format!("Top Speed: {}", self.top_speed).as_str(),
+            450.,
+            550.,
+            32.,
+            RED,
+        );
+        draw_text(
+            format!("Mean Time: {} sec", travel_times.mean).as_str(),
+            450.,
+            600.,
+            32.,
+            RED,
+        );
+        draw_text(
+            format!("Median Time: {} sec", travel_times.median).as_str(),
+            450.,
+            650.,
+            32.,
+            RED,
+        );
+        draw_text(
+            format!("95th Percentile Time: {} sec", travel_times.p95).as_str(),
+            450.,
+            700.,
+            32.,
+            RED,
+        );
         draw_text(
             "At this point there is no return, press Esc to exit as if you have a choice :)",
             150.,
@@ -123,5 +386,180 @@ impl Stats {
             32.,
             RED,
         );
+        draw_text(
+            format!("Throughput: {:.1} cars/min", self.throughput).as_str(),
+            850.,
+            400.,
+            32.,
+            RED,
+        );
+        draw_text(
+            format!("Max In Core: {}", self.max_in_core).as_str(),
+            850.,
+            450.,
+            32.,
+            RED,
+        );
+        draw_text(
+            format!("Cars Requested: {}", self.requested_cars).as_str(),
+            850.,
+            500.,
+            32.,
+            RED,
+        );
+        draw_text(
+            format!("Mean Entry Delay: {:.2} sec", self.entry_delays.mean()).as_str(),
+            850.,
+            550.,
+            32.,
+            RED,
+        );
+        draw_text(
+            format!("Mean Waiting Time: {:.2} sec", self.waiting_times.mean()).as_str(),
+            850.,
+            600.,
+            32.,
+            RED,
+        );
+        draw_text(
+            format!("Stops Per Car: {:.2}", self.mean_stops()).as_str(),
+            850.,
+            650.,
+            32.,
+            RED,
+        );
+        draw_text(
+            format!("Cars Never Stopped: {}", self.unstopped_cars).as_str(),
+            850.,
+            700.,
+            32.,
+            RED,
+        );
+        draw_text(
+            format!(
+                "Emergency Delay: {:.2} sec over {}",
+                self.emergency_delays.mean(),
+                self.emergency_delays.count
+            )
+            .as_str(),
+            850.,
+            750.,
+            32.,
+            RED,
+        );
+    }
+}
+
+impl Default for Stats {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+// The end of run report printed by headless runs
+impl fmt::Display for Stats {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "Total Cars Arrived: {}", self.total_cars)?;
+        writeln!(f, "Best Time: {}", or_none(self.best_time, " sec"))?;
+        writeln!(f, "Worst Time: {} sec", self.worst_time)?;
+        writeln!(f, "Best Velocity: {}", self.best_velocity)?;
+        writeln!(f, "Worst Velocity: {}", or_none(self.worst_velocity, ""))?;
+        writeln!(f, "Top Speed: {}", self.top_speed)?;
+        writeln!(f, "Collision: {}", self.collisions)?;
+        writeln!(f, "Close Calls: {}", self.close_calls)?;
+        writeln!(f, "Throughput: {} cars/min", self.throughput)?;
+        writeln!(f, "Max In Core: {}", self.max_in_core)?;
+        writeln!(f, "Cars Requested: {}", self.requested_cars)?;
+        writeln!(f, "Queued Cars: {}", self.queued_cars)?;
+        writeln!(f, "Max Queued Cars: {}", self.max_queued_cars)?;
+        writeln!(f, "Entry Delays: {}", self.entry_delays)?;
+        writeln!(f, "Waiting Times: {}", self.waiting_times)?;
+        writeln!(
+            f,
+            "Stops: {} in all, {} per car, at most {}, {} cars never stopped",
+            self.stops,
+            self.mean_stops(),
+            self.max_stops,
+            self.unstopped_cars
+        )?;
+        writeln!(f, "Emergency Delays: {}", self.emergency_delays)?;
+        writeln!(f, "Travel Times: {}", self.travel_times)?;
+        for (route, travel_times) in &self.per_route {
+            writeln!(f, "  Route {}: {}", route, travel_times)?;
+        }
+        for (approach, travel_times) in &self.per_approach {
+            writeln!(f, "  Approach {}: {}", approach.letter(), travel_times)?;
+        }
+        for (class, travel_times) in &self.per_class {
+            writeln!(f, "  Class {}: {}", class.name(), travel_times)?;
+        }
+        Ok(())
+    }
+}
+
+// The value with its unit, or "none" if there is none yet
+fn or_none(value: Option<f32>, unit: &str) -> String {
+    match value {
+        Some(value) => format!("{}{}", value, unit),
+        None => String::from("none"),
+    }
+}
+
+impl fmt::Display for Durations {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let summary = self.summary();
+        write!(
+            f,
+            "{} cars, mean {} sec, median {} sec, p90 {} sec, p95 {} sec, p99 {} sec",
+            summary.count, summary.mean, summary.median, summary.p90, summary.p95, summary.p99
+        )
+    }
+}
+
+// The stats of a finished run along with what it takes to repeat it
+#[derive(Debug, Serialize)]
+pub struct Report<'a> {
+    pub seed: u64,
+    pub controller: &'static str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub scenario: Option<&'a str>,
+    pub stats: &'a Stats,
+}
+
+impl Report<'_> {
+    pub fn to_json(&self) -> String {
+        serde_json::to_string_pretty(self).unwrap()
+    }
+}
+
+impl fmt::Display for Report<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "Seed: {}", self.seed)?;
+        writeln!(f, "Controller: {}", self.controller)?;
+        if let Some(scenario) = self.scenario {
+            writeln!(f, "Scenario: {}", scenario)?;
+        }
+        writeln!(f, "Simulated Time: {:.1} sec", self.stats.simulated_time)?;
+        write!(f, "{}", self.stats)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Durations come in any order, the percentiles are of the sorted ones
+    #[test]
+    fn summary_of_unsorted_durations() {
+        let mut durations = Durations::default();
+        for duration in [5., 1., 4., 2., 3.] {
+            durations.add(duration);
+        }
+        let summary = durations.summary();
+        assert_eq!(summary.count, 5);
+        assert_eq!(summary.mean, 3.);
+        assert_eq!(summary.median, 3.);
+        assert_eq!(summary.p90, 4.6);
+        assert_eq!(Durations::default().summary(), Summary::default());
     }
 }
@@ -0,0 +1,101 @@
+use crate::car::*;
+use crate::route::*;
+use crate::vehicles::*;
+use serde::Serialize;
+use std::fmt::Write;
+use std::fs;
+
+// A car's trip from its spawn point until it left the road, in simulated
+// seconds and pixels
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Trip {
+    pub id: u64,
+    pub route: Route,
+    pub class: VehicleClass,
+    pub emergency: bool,
+    pub spawn_time: f32,
+    // Time spent in the entry queue before spawning
+    pub entry_delay: f32,
+    pub exit_time: f32,
+    pub travel_time: f32,
+    // Travel time lost against driving the whole way at its cruising speed
+    pub delay: f32,
+    pub distance: f32,
+    pub min_speed: f32,
+    pub max_speed: f32,
+    pub avg_speed: f32,
+    pub waiting_time: f32,
+    // Times the car came to a stop
+    pub stops: u32,
+    pub close_calls: u32,
+}
+
+impl Trip {
+    pub fn new(car: &Car, exit_time: f32) -> Self {
+        let travel_time = exit_time - car.spawn_time;
+        Trip {
+            id: car.id,
+            route: car.route,
+            class: car.class,
+            emergency: car.is_emergency,
+            spawn_time: car.spawn_time,
+            entry_delay: car.entry_delay,
+            exit_time,
+            travel_time,
+            delay: travel_time - car.travelled / car.randomized_initial_speed,
+            distance: car.travelled,
+            min_speed: car.lowest_speed,
+            max_speed: car.peak_speed,
+            avg_speed: car.travelled / travel_time,
+            waiting_time: car.waiting_time,
+            stops: car.stops,
+            close_calls: car.close_calls,
+        }
+    }
+}
+
+const CSV_HEADER: &str = "id,route,class,emergency,spawn_time,entry_delay,exit_time,travel_time,\
+delay,distance,min_speed,max_speed,avg_speed,waiting_time,stops,close_calls";
+
+pub fn trips_to_csv(trips: &[Trip]) -> String {
+    let mut csv = String::from(CSV_HEADER);
+    csv.push('\n');
+    for trip in trips {
+        writeln!(
+            csv,
+            "{},{},{},{},{},{},{},{},{},{},{},{},{},{},{},{}",
+            trip.id,
+            trip.route,
+            trip.class.name(),
+            trip.emergency,
+            trip.spawn_time,
+            trip.entry_delay,
+            trip.exit_time,
+            trip.travel_time,
+            trip.delay,
+            trip.distance,
+            trip.min_speed,
+            trip.max_speed,
+            trip.avg_speed,
+            trip.waiting_time,
+            trip.stops,
+            trip.close_calls
+        )
+        .unwrap();
+    }
+    csv
+}
+
+pub fn trips_to_json(trips: &[Trip]) -> String {
+    serde_json::to_string_pretty(trips).unwrap()
+}
+
+// Writes the trips as JSON if the path ends in .json, as CSV otherwise
+pub fn export_trips(trips: &[Trip], path: &str) -> Result<(), String> {
+    let contents = if path.ends_with(".json") {
+        trips_to_json(trips)
+    } else {
+        trips_to_csv(trips)
+    };
+    fs::write(path, contents).map_err(|err| format!("cannot write {}: {}", path, err))
+}
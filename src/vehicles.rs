@@ -0,0 +1,234 @@
+use crate::car::*;
+use macroquad::{prelude::*, rand::gen_range};
+use serde::{Deserialize, Serialize};
+
+// The sprite is drawn this much shorter and narrower than the body
+pub const SPRITE_INSET: f32 = 3.;
+
+// The kinds of vehicle on the road, written as their name, see `name`
+#[derive(
+    Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord, Default, Deserialize, Serialize,
+)]
+#[serde(try_from = "String", into = "String")]
+pub enum VehicleClass {
+    #[default]
+    Car,
+    Van,
+    Bus,
+    Truck,
+    Motorcycle,
+}
+
+// Size and dynamics of a vehicle class, in pixels and simulated seconds.
+// The lanes are laid out for cars. The longer classes are a little narrower,
+// where their turns still swing close to the next lane the conflict matrix
+// keeps the two routes apart.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct VehicleSpec {
+    pub length: f32,
+    pub width: f32,
+    // Cruising speeds are drawn from MIN_SPEED up to this
+    pub max_speed: f32,
+    pub acceleration: f32,
+    // Braking the car following model is comfortable with, and the hardest
+    // the vehicle can brake
+    pub braking: f32,
+    pub max_braking: f32,
+}
+
+impl VehicleSpec {
+    pub fn size(&self) -> Dimensions {
+        Dimensions {
+            long_edge: self.length,
+            short_edge: self.width,
+        }
+    }
+
+    // How far ahead the radar reaches: far enough to come to a stop with
+    // comfortable braking from the top speed and keep the standstill gap
+    pub fn radar_range(&self) -> f32 {
+        self.max_speed.powi(2) / (2. * self.braking) + STANDSTILL_GAP
+    }
+}
+
+impl VehicleClass {
+    pub const ALL: [VehicleClass; 5] = [
+        VehicleClass::Car,
+        VehicleClass::Van,
+        VehicleClass::Bus,
+        VehicleClass::Truck,
+        VehicleClass::Motorcycle,
+    ];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            VehicleClass::Car => "car",
+            VehicleClass::Van => "van",
+            VehicleClass::Bus => "bus",
+            VehicleClass::Truck => "truck",
+            VehicleClass::Motorcycle => "motorcycle",
+        }
+    }
+
+    pub fn spec(self) -> VehicleSpec {
+        match self {
+            VehicleClass::Car => VehicleSpec {
+                length: CAR_SIZE.x,
+                width: CAR_SIZE.y,
+                max_speed: MAX_SPEED,
+                acceleration: MAX_ACCELERATION,
+                braking: COMFORTABLE_BRAKING,
+                max_braking: MAX_BRAKING,
+            },
+            VehicleClass::Van => VehicleSpec {
+                length: 47.,
+                width: 32.,
+                max_speed: 105.,
+                acceleration: 50.,
+                braking: 110.,
+                max_braking: 420.,
+            },
+            VehicleClass::Bus => VehicleSpec {
+                length: 62.,
+                width: 29.,
+                max_speed: 85.,
+                acceleration: 35.,
+                braking: 90.,
+                max_braking: 360.,
+            },
+            VehicleClass::Truck => VehicleSpec {
+                length: 58.,
+                width: 30.,
+                max_speed: 80.,
+                acceleration: 30.,
+                braking: 80.,
+                max_braking: 320.,
+            },
+            VehicleClass::Motorcycle => VehicleSpec {
+                length: 26.,
+                width: 14.,
+                max_speed: MAX_SPEED,
+                acceleration: 90.,
+                braking: 140.,
+                max_braking: 520.,
+            },
+        }
+    }
+
+    // A cruising speed drawn from MIN_SPEED up to the top speed of the class
+    pub fn random_speed(self) -> f32 {
+        gen_range(MIN_SPEED, self.spec().max_speed)
+    }
+
+    pub fn sprite_path(self) -> String {
+        format!("assets/{}.png", self.name())
+    }
+
+    // Size the sprite is drawn at
+    pub fn sprite_size(self) -> Vec2 {
+        let spec = self.spec();
+        vec2(spec.length - SPRITE_INSET, spec.width - SPRITE_INSET)
+    }
+}
+
+impl TryFrom<String> for VehicleClass {
+    type Error = String;
+
+    fn try_from(name: String) -> Result<Self, Self::Error> {
+        VehicleClass::ALL
+            .into_iter()
+            .find(|class| class.name() == name)
+            .ok_or_else(|| format!("unknown vehicle class {:?}", name))
+    }
+}
+
+impl From<VehicleClass> for String {
+    fn from(class: VehicleClass) -> Self {
+        class.name().to_string()
+    }
+}
+
+// Relative share of the spawned vehicles of each class. Classes left out
+// of a demand file get no share, while without any the default mix holds.
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+pub struct ClassMix {
+    #[serde(default)]
+    pub car: f32,
+    #[serde(default)]
+    pub van: f32,
+    #[serde(default)]
+    pub bus: f32,
+    #[serde(default)]
+    pub truck: f32,
+    #[serde(default)]
+    pub motorcycle: f32,
+}
+
+impl Default for ClassMix {
+    fn default() -> Self {
+        ClassMix {
+            car: 70.,
+            van: 10.,
+            bus: 5.,
+            truck: 5.,
+            motorcycle: 10.,
+        }
+    }
+}
+
+impl ClassMix {
+    // No share for any class yet, to be filled in
+    pub fn empty() -> Self {
+        ClassMix {
+            car: 0.,
+            van: 0.,
+            bus: 0.,
+            truck: 0.,
+            motorcycle: 0.,
+        }
+    }
+
+    pub fn weight(&self, class: VehicleClass) -> f32 {
+        match class {
+            VehicleClass::Car => self.car,
+            VehicleClass::Van => self.van,
+            VehicleClass::Bus => self.bus,
+            VehicleClass::Truck => self.truck,
+            VehicleClass::Motorcycle => self.motorcycle,
+        }
+    }
+
+    pub fn weight_mut(&mut self, class: VehicleClass) -> &mut f32 {
+        match class {
+            VehicleClass::Car => &mut self.car,
+            VehicleClass::Van => &mut self.van,
+            VehicleClass::Bus => &mut self.bus,
+            VehicleClass::Truck => &mut self.truck,
+            VehicleClass::Motorcycle => &mut self.motorcycle,
+        }
+    }
+
+    // A class drawn in proportion to the weights, a car if they are all
+    // zero. Nothing is drawn when only one class has a share, so runs of a
+    // single class take the same random numbers as before there were any.
+    pub fn pick(&self) -> VehicleClass {
+        let weighted: Vec<VehicleClass> = VehicleClass::ALL
+            .into_iter()
+            .filter(|&class| self.weight(class) > 0.)
+            .collect();
+        match weighted[..] {
+            [] => return VehicleClass::Car,
+            [class] => return class,
+            _ => (),
+        }
+        let total: f32 = weighted.iter().map(|&class| self.weight(class)).sum();
+        let mut draw = gen_range(0., total);
+        for class in VehicleClass::ALL {
+            if draw < self.weight(class) {
+                return class;
+            }
+            draw -= self.weight(class);
+        }
+        VehicleClass::Car
+    }
+}